@@ -0,0 +1,693 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Error;
+
+// Package archive format
+//
+// A package archive is a zstd-compressed tar stream. The first entry of the
+// stream is a TOML file named PKGINFO which lists every file and symbolic link
+// of the package, the remaining entries are the contents. See "Package archive
+// format" in README.md for the full specification
+pub static ARCHIVE_FORMAT: u32 = 1;
+pub static PKGINFO:        &str = "PKGINFO";
+
+// upper bound for the size of PKGINFO, it only contains metadata
+static MAX_PKGINFO_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    ReadError(String),
+    WriteError(String),
+
+    // archive is not a zstd-compressed tar stream
+    InvalidArchive,
+    MissingPkgInfo,
+    InvalidPkgInfo(String),
+    UnsupportedFormat(u32),
+    UnsafePath(String),
+    UnsupportedEntry(String),
+    DuplicateEntry(String),
+
+    // entry of the archive is not listed in PKGINFO
+    UnlistedEntry(String),
+
+    // entry listed in PKGINFO is not in the archive
+    MissingEntry(String),
+    ChecksumMismatch(String),
+
+    // PKGINFO describes a different package than the signed metadata
+    PackageMismatch {
+        expected: String,
+        found:    String
+    }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::ReadError(path)         => write!(f, "{}: failed to read file", path),
+            ArchiveError::WriteError(path)        => write!(f, "{}: failed to write file", path),
+            ArchiveError::InvalidArchive          => write!(f, "invalid package archive"),
+            ArchiveError::MissingPkgInfo          => write!(f, "package archive does not start with {}", PKGINFO),
+            ArchiveError::InvalidPkgInfo(msg)     => write!(f, "{}: {}", PKGINFO, msg),
+            ArchiveError::UnsupportedFormat(ver)  => write!(f, "unsupported package archive format {}", ver),
+            ArchiveError::UnsafePath(path)        => write!(f, "{}: path points outside of the package", path),
+            ArchiveError::UnsupportedEntry(path)  => write!(f, "{}: unsupported file type", path),
+            ArchiveError::DuplicateEntry(path)    => write!(f, "{}: duplicate entry", path),
+            ArchiveError::UnlistedEntry(path)     => write!(f, "{}: file is not listed in {}", path, PKGINFO),
+            ArchiveError::MissingEntry(path)      => write!(f, "{}: file is missing from the archive", path),
+            ArchiveError::ChecksumMismatch(path)  => write!(f, "{}: checksum mismatch", path),
+            ArchiveError::PackageMismatch { expected, found } => {
+                write!(f, "archive contains {} instead of {}", found, expected)
+            }
+        }
+    }
+}
+
+impl From<ArchiveError> for Error {
+    fn from(err: ArchiveError) -> Self {
+        Error::Archive(err)
+    }
+}
+
+// Contents of PKGINFO
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchiveInfo {
+    pub format:  u32,
+    pub name:    String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files:   Vec<ArchiveFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links:   Vec<ArchiveLink>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchiveFile {
+    pub path:   String,
+    pub sha256: String,
+    pub mode:   u32
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchiveLink {
+    pub path:   String,
+    pub target: String
+}
+
+// File of a verified archive, the contents are read with unpack()
+#[derive(Debug)]
+pub struct PackageFile {
+    pub path:   PathBuf,
+    pub sha256: String,
+    pub mode:   u32
+}
+
+// Verified contents of a package archive
+#[derive(Debug)]
+pub struct Package {
+    pub name:    String,
+    pub version: String,
+
+    // archive the package was read from
+    pub path:    PathBuf,
+    pub files:   Vec<PackageFile>,
+    pub links:   Vec<ArchiveLink>
+}
+
+// Reader that computes the sha256 digest of everything read through it
+struct HashReader<R: Read> {
+    inner:  R,
+    sha256: Sha256
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.sha256.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+// Convert an archive path to a relative path
+//
+// Only plain path components are accepted, absolute paths and ".." could
+// otherwise be used to write files outside of the installation prefix
+pub fn relative_path(path: &Path) -> Option<PathBuf> {
+    let mut res = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => res.push(part),
+            Component::CurDir       => { },
+            _                       => return None
+        }
+    }
+
+    if res.as_os_str().is_empty() {
+        return None;
+    }
+
+    Some(res)
+}
+
+// Symbolic links must be relative and must not point outside of the package root
+fn link_target_is_safe(path: &Path, target: &Path) -> bool {
+    let mut depth = path.components().count() as i64 - 1;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir    => { },
+            Component::ParentDir => depth -= 1,
+            _                    => return false
+        }
+
+        if depth < 0 {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Check that the entries listed in PKGINFO are well-formed and unique
+fn check_info(info: &ArchiveInfo) -> Result<(), ArchiveError> {
+    if info.format != ARCHIVE_FORMAT {
+        return Err(ArchiveError::UnsupportedFormat(info.format));
+    }
+
+    let mut seen = HashSet::new();
+
+    let paths = info.files.iter().map(|f| &f.path).chain(info.links.iter().map(|l| &l.path));
+
+    for path in paths {
+        match relative_path(Path::new(path)) {
+            Some(rel) if rel.as_os_str() == path.as_str() => { },
+            _ => return Err(ArchiveError::UnsafePath(path.clone()))
+        }
+
+        if !seen.insert(path) {
+            return Err(ArchiveError::DuplicateEntry(path.clone()));
+        }
+    }
+
+    for link in info.links.iter() {
+        if !link_target_is_safe(Path::new(&link.path), Path::new(&link.target)) {
+            return Err(ArchiveError::UnsafePath(link.path.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// Read the package archive of package `name` version `version` from `reader`
+//
+// The contents of each file are passed to `unpack` as they are read, so that
+// no file is held in memory. The file is checked against its PKGINFO entry
+// only once `unpack` returns, so a file that fails the check may already be
+// written and it's up to the caller to discard it if an error is returned
+//
+// The files and links of the archive are returned only if the archive is
+// well-formed, PKGINFO matches `name` and `version`, and every file and link
+// of the archive matches its PKGINFO entry
+fn read<R, F>(reader: R, name: &str, version: &str, mut unpack: F) -> Result<(Vec<PackageFile>, Vec<ArchiveLink>), ArchiveError>
+where
+    R: Read,
+    F: FnMut(&PackageFile, &mut dyn Read) -> Result<(), ArchiveError>
+{
+    let decoder = zstd::stream::read::Decoder::new(reader).map_err(|_| ArchiveError::InvalidArchive)?;
+    let mut ar  = tar::Archive::new(decoder);
+    let mut entries = ar.entries().map_err(|_| ArchiveError::InvalidArchive)?;
+
+    let info: ArchiveInfo = match entries.next() {
+        Some(Ok(entry)) => {
+            let is_pkginfo = entry.path().map(|p| p.as_os_str() == PKGINFO).unwrap_or(false);
+
+            if !is_pkginfo || entry.header().entry_type() != tar::EntryType::Regular {
+                return Err(ArchiveError::MissingPkgInfo);
+            }
+
+            let mut contents = String::new();
+            entry.take(MAX_PKGINFO_SIZE)
+                .read_to_string(&mut contents)
+                .map_err(|_| ArchiveError::InvalidArchive)?;
+
+            toml::from_str(&contents).map_err(|err| ArchiveError::InvalidPkgInfo(err.to_string()))?
+        },
+        Some(Err(_)) => return Err(ArchiveError::InvalidArchive),
+        None         => return Err(ArchiveError::MissingPkgInfo)
+    };
+
+    check_info(&info)?;
+
+    if info.name != name || info.version != version {
+        return Err(ArchiveError::PackageMismatch {
+            expected: format!("{} {}", name, version),
+            found:    format!("{} {}", info.name, info.version)
+        });
+    }
+
+    let listed_files: HashMap<&str, &ArchiveFile> = info.files.iter().map(|f| (f.path.as_str(), f)).collect();
+    let listed_links: HashMap<&str, &ArchiveLink> = info.links.iter().map(|l| (l.path.as_str(), l)).collect();
+
+    let mut files: Vec<PackageFile> = Vec::new();
+    let mut links: Vec<ArchiveLink> = Vec::new();
+
+    for entry in entries {
+        let mut entry = entry.map_err(|_| ArchiveError::InvalidArchive)?;
+        let path      = entry.path().map_err(|_| ArchiveError::InvalidArchive)?.into_owned();
+
+        let rel = match relative_path(&path) {
+            Some(rel) => rel,
+            None      => return Err(ArchiveError::UnsafePath(path.display().to_string()))
+        };
+
+        let key = rel.display().to_string();
+
+        match entry.header().entry_type() {
+            // directories are created implicitly for the files they contain
+            tar::EntryType::Directory => { },
+            tar::EntryType::Regular => {
+                let listed = match listed_files.get(key.as_str()) {
+                    Some(listed) => listed,
+                    None         => return Err(ArchiveError::UnlistedEntry(key))
+                };
+
+                if files.iter().any(|f| f.path == rel) {
+                    return Err(ArchiveError::DuplicateEntry(key));
+                }
+
+                let file = PackageFile {
+                    path:   rel,
+                    sha256: listed.sha256.clone(),
+                    mode:   listed.mode & 0o7777
+                };
+
+                let mut data = HashReader { inner: &mut entry, sha256: Sha256::new() };
+
+                unpack(&file, &mut data)?;
+
+                // the rest of the file, if `unpack` didn't read all of it
+                io::copy(&mut data, &mut io::sink()).map_err(|_| ArchiveError::InvalidArchive)?;
+
+                if format!("{:x}", data.sha256.finalize()) != file.sha256 {
+                    return Err(ArchiveError::ChecksumMismatch(key));
+                }
+
+                files.push(file);
+            },
+            tar::EntryType::Symlink => {
+                let listed = match listed_links.get(key.as_str()) {
+                    Some(listed) => listed,
+                    None         => return Err(ArchiveError::UnlistedEntry(key))
+                };
+
+                let target = entry.link_name().map_err(|_| ArchiveError::InvalidArchive)?;
+
+                if target.map(|t| t.as_os_str() != listed.target.as_str()).unwrap_or(true) {
+                    return Err(ArchiveError::ChecksumMismatch(key));
+                }
+
+                if links.iter().any(|l| l.path == key) {
+                    return Err(ArchiveError::DuplicateEntry(key));
+                }
+
+                links.push((*listed).clone());
+            },
+            _ => return Err(ArchiveError::UnsupportedEntry(key))
+        }
+    }
+
+    for file in info.files.iter() {
+        if !files.iter().any(|f| f.path.as_os_str() == file.path.as_str()) {
+            return Err(ArchiveError::MissingEntry(file.path.clone()));
+        }
+    }
+
+    for link in info.links.iter() {
+        if !links.iter().any(|l| l.path == link.path) {
+            return Err(ArchiveError::MissingEntry(link.path.clone()));
+        }
+    }
+
+    Ok((files, links))
+}
+
+// Open and verify the package archive `path` of package `name` version `version`
+//
+// The contents of the files are checked but not kept, see unpack(). The caller
+// is responsible for checking that the archive itself is the one that was signed
+pub fn open(path: &Path, name: &str, version: &str) -> Result<Package, ArchiveError> {
+    let file = File::open(path).map_err(|_| ArchiveError::ReadError(path.display().to_string()))?;
+    let (files, links) = read(io::BufReader::new(file), name, version, |_, _| Ok(()))?;
+
+    Ok(Package {
+        name:    name.to_string(),
+        version: version.to_string(),
+        path:    path.to_path_buf(),
+        files,
+        links
+    })
+}
+
+// Read the archive of verified package `package` again and pass the contents
+// of each file to `unpack`, see read()
+//
+// The archive is verified again while it's read and every file must match the
+// file of `package`, so a file that was changed since open() isn't unpacked
+// without an error
+pub fn unpack<F>(package: &Package, mut unpack: F) -> Result<(), ArchiveError>
+where
+    F: FnMut(&PackageFile, &mut dyn Read) -> Result<(), ArchiveError>
+{
+    let file = File::open(&package.path).map_err(|_| ArchiveError::ReadError(package.path.display().to_string()))?;
+
+    let (files, _) = read(io::BufReader::new(file), &package.name, &package.version, |file, data| {
+        if !package.files.iter().any(|f| f.path == file.path && f.sha256 == file.sha256) {
+            return Err(ArchiveError::UnlistedEntry(file.path.display().to_string()));
+        }
+
+        unpack(file, data)
+    })?;
+
+    // read() rejects duplicates, so the same number of files means the same files
+    if files.len() != package.files.len() {
+        return Err(ArchiveError::InvalidArchive);
+    }
+
+    Ok(())
+}
+
+// Collect the regular files and symbolic links under `dir` in a stable order
+fn walk(root: &Path, dir: &Path, info: &mut ArchiveInfo) -> Result<(), ArchiveError> {
+    let read_err = |path: &Path| ArchiveError::ReadError(path.display().to_string());
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|_| read_err(dir))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, io::Error>>()
+        .map_err(|_| read_err(dir))?;
+
+    entries.sort();
+
+    for path in entries {
+        let meta = fs::symlink_metadata(&path).map_err(|_| read_err(&path))?;
+        let rel  = path.strip_prefix(root).unwrap().display().to_string();
+
+        if meta.file_type().is_dir() {
+            walk(root, &path, info)?;
+        } else if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path).map_err(|_| read_err(&path))?;
+
+            info.links.push(ArchiveLink {
+                path:   rel,
+                target: target.display().to_string()
+            });
+        } else if meta.file_type().is_file() {
+            let mut sha256 = Sha256::new();
+            let mut file   = File::open(&path).map_err(|_| read_err(&path))?;
+            io::copy(&mut file, &mut sha256).map_err(|_| read_err(&path))?;
+
+            info.files.push(ArchiveFile {
+                path:   rel,
+                sha256: format!("{:x}", sha256.finalize()),
+                mode:   meta.permissions().mode() & 0o7777
+            });
+        } else {
+            return Err(ArchiveError::UnsupportedEntry(rel));
+        }
+    }
+
+    Ok(())
+}
+
+// Build the package archive of package `name` version `version` from the
+// staging directory `staging` and write it to `writer`
+//
+// The staging directory is laid out like the installation prefix, e.g., the
+// binaries of the package go to <staging>/bin. Ownership and timestamps are
+// not recorded, so building the same staging directory twice gives the same archive
+pub fn write<W: Write>(staging: &str, name: &str, version: &str, writer: W) -> Result<W, ArchiveError> {
+    let root = Path::new(staging);
+    let mut info = ArchiveInfo {
+        format:  ARCHIVE_FORMAT,
+        name:    name.to_string(),
+        version: version.to_string(),
+        files:   Vec::new(),
+        links:   Vec::new()
+    };
+
+    walk(root, root, &mut info)?;
+    check_info(&info)?;
+
+    let write_err = || ArchiveError::WriteError(staging.to_string());
+    let pkginfo   = toml::to_string(&info).map_err(|err| ArchiveError::InvalidPkgInfo(err.to_string()))?;
+    let encoder   = zstd::stream::write::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(|_| write_err())?;
+    let mut tar   = tar::Builder::new(encoder);
+
+    let header = |entry_type: tar::EntryType, size: u64, mode: u32| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(mode);
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        header
+    };
+
+    tar.append_data(
+        &mut header(tar::EntryType::Regular, pkginfo.len() as u64, 0o644),
+        PKGINFO,
+        pkginfo.as_bytes()
+    ).map_err(|_| write_err())?;
+
+    for file in info.files.iter() {
+        let path = root.join(&file.path);
+        let data = File::open(&path).map_err(|_| ArchiveError::ReadError(path.display().to_string()))?;
+        let size = data.metadata().map_err(|_| ArchiveError::ReadError(path.display().to_string()))?.len();
+
+        tar.append_data(&mut header(tar::EntryType::Regular, size, file.mode), &file.path, data)
+            .map_err(|_| write_err())?;
+    }
+
+    for link in info.links.iter() {
+        tar.append_link(&mut header(tar::EntryType::Symlink, 0, 0o777), &link.path, &link.target)
+            .map_err(|_| write_err())?;
+    }
+
+    tar.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map_err(|_| write_err())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    enum Entry<'a> {
+        File(&'a str, &'a [u8]),
+        Link(&'a str, &'a str)
+    }
+
+    // Write an archive of `entries` after PKGINFO `info` without any of the
+    // checks of write(), entry names are written as they are
+    fn craft(info: &ArchiveInfo, entries: &[Entry]) -> PathBuf {
+        let path    = testing::temp_dir().join("crafted.pkg.tar.zst");
+        let encoder = zstd::stream::write::Encoder::new(File::create(&path).unwrap(), 0).unwrap();
+        let mut tar = tar::Builder::new(encoder);
+
+        let mut append = |name: &str, entry_type: tar::EntryType, link: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_gnu_mut().unwrap().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append(&header, data).unwrap();
+        };
+
+        append(PKGINFO, tar::EntryType::Regular, "", toml::to_string(info).unwrap().as_bytes());
+
+        for entry in entries {
+            match entry {
+                Entry::File(name, data)   => append(name, tar::EntryType::Regular, "", data),
+                Entry::Link(name, target) => append(name, tar::EntryType::Symlink, target, b"")
+            }
+        }
+
+        tar.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    fn info(files: &[(&str, &[u8])], links: &[(&str, &str)]) -> ArchiveInfo {
+        ArchiveInfo {
+            format:  ARCHIVE_FORMAT,
+            name:    "hello".to_string(),
+            version: "1.0".to_string(),
+            files:   files.iter().map(|(path, data)| {
+                let mut sha256 = Sha256::new();
+                sha256.update(data);
+
+                ArchiveFile { path: path.to_string(), sha256: format!("{:x}", sha256.finalize()), mode: 0o644 }
+            }).collect(),
+            links:   links.iter().map(|(path, target)| {
+                ArchiveLink { path: path.to_string(), target: target.to_string() }
+            }).collect()
+        }
+    }
+
+    fn open_err(path: &Path) -> ArchiveError {
+        open(path, "hello", "1.0").unwrap_err()
+    }
+
+    #[test]
+    fn written_archive_unpacks() {
+        let dir     = testing::temp_dir();
+        let package = testing::package(&dir, "hello", "1.0", &[("bin/hello", "echo hi"), ("README", "hi")]);
+        let mut unpacked: Vec<(PathBuf, String)> = Vec::new();
+
+        unpack(&package, |file, data| {
+            let mut contents = String::new();
+            data.read_to_string(&mut contents).unwrap();
+            unpacked.push((file.path.clone(), contents));
+            Ok(())
+        }).unwrap();
+
+        unpacked.sort();
+        assert_eq!(unpacked, vec![
+            (PathBuf::from("README"), "hi".to_string()),
+            (PathBuf::from("bin/hello"), "echo hi".to_string())
+        ]);
+    }
+
+    #[test]
+    fn crafted_archive_opens() {
+        let path = craft(
+            &info(&[("bin/hello", b"echo hi")], &[("bin/hi", "hello")]),
+            &[Entry::File("bin/hello", b"echo hi"), Entry::Link("bin/hi", "hello")]
+        );
+
+        let package = open(&path, "hello", "1.0").unwrap();
+        assert_eq!(package.files.len(), 1);
+        assert_eq!(package.links.len(), 1);
+    }
+
+    #[test]
+    fn other_package_is_rejected() {
+        let path = craft(&info(&[], &[]), &[]);
+
+        assert!(matches!(open(&path, "hello", "2.0"), Err(ArchiveError::PackageMismatch { .. })));
+    }
+
+    #[test]
+    fn parent_dir_in_pkginfo_is_rejected() {
+        let path = craft(&info(&[("../evil", b"x")], &[]), &[Entry::File("../evil", b"x")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::UnsafePath(_)));
+    }
+
+    #[test]
+    fn parent_dir_entry_is_rejected() {
+        let path = craft(&info(&[("evil", b"x")], &[]), &[Entry::File("a/../../evil", b"x")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::UnsafePath(_)));
+    }
+
+    #[test]
+    fn absolute_entry_is_rejected() {
+        let path = craft(&info(&[("etc/passwd", b"x")], &[]), &[Entry::File("/etc/passwd", b"x")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::UnsafePath(_)));
+    }
+
+    #[test]
+    fn absolute_symlink_is_rejected() {
+        let path = craft(&info(&[], &[("passwd", "/etc/passwd")]), &[Entry::Link("passwd", "/etc/passwd")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::UnsafePath(_)));
+    }
+
+    #[test]
+    fn escaping_symlink_is_rejected() {
+        let path = craft(&info(&[], &[("bin/up", "../../etc")]), &[Entry::Link("bin/up", "../../etc")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::UnsafePath(_)));
+    }
+
+    #[test]
+    fn symlink_target_must_match_pkginfo() {
+        let path = craft(&info(&[], &[("bin/hi", "hello")]), &[Entry::Link("bin/hi", "/etc/passwd")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::ChecksumMismatch(_)));
+    }
+
+    #[test]
+    fn staging_with_absolute_symlink_is_rejected() {
+        let dir = testing::temp_dir();
+
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("passwd")).unwrap();
+
+        assert!(matches!(
+            write(dir.to_str().unwrap(), "hello", "1.0", Vec::new()),
+            Err(ArchiveError::UnsafePath(_))
+        ));
+    }
+
+    #[test]
+    fn modified_file_is_rejected() {
+        let path = craft(&info(&[("bin/hello", b"echo hi")], &[]), &[Entry::File("bin/hello", b"rm -rf")]);
+
+        assert!(matches!(open_err(&path), ArchiveError::ChecksumMismatch(_)));
+    }
+
+    #[test]
+    fn unlisted_and_missing_entries_are_rejected() {
+        let unlisted = craft(&info(&[], &[]), &[Entry::File("bin/evil", b"x")]);
+        let missing  = craft(&info(&[("bin/hello", b"echo hi")], &[]), &[]);
+
+        assert!(matches!(open_err(&unlisted), ArchiveError::UnlistedEntry(_)));
+        assert!(matches!(open_err(&missing), ArchiveError::MissingEntry(_)));
+    }
+
+    #[test]
+    fn duplicate_entry_is_rejected() {
+        let path = craft(
+            &info(&[("bin/hello", b"echo hi")], &[]),
+            &[Entry::File("bin/hello", b"echo hi"), Entry::File("bin/hello", b"echo hi")]
+        );
+
+        assert!(matches!(open_err(&path), ArchiveError::DuplicateEntry(_)));
+    }
+
+    #[test]
+    fn not_an_archive() {
+        let path = testing::temp_dir().join("garbage");
+        fs::write(&path, "garbage").unwrap();
+
+        assert!(matches!(open_err(&path), ArchiveError::InvalidArchive));
+    }
+
+    #[test]
+    fn archive_changed_after_open_is_not_unpacked() {
+        let path    = craft(&info(&[("bin/hello", b"echo hi")], &[]), &[Entry::File("bin/hello", b"echo hi")]);
+        let package = open(&path, "hello", "1.0").unwrap();
+
+        let other = craft(&info(&[("bin/hello", b"rm -rf")], &[]), &[Entry::File("bin/hello", b"rm -rf")]);
+        fs::rename(other, &path).unwrap();
+
+        assert!(matches!(unpack(&package, |_, _| Ok(())), Err(ArchiveError::UnlistedEntry(_))));
+    }
+}
@@ -4,57 +4,219 @@ extern crate common;
 extern crate actix_rt;
 
 use ring::signature;
+use ring::signature::KeyPair;
+use clap::{App, Arg, ArgGroup, ArgMatches, AppSettings};
+use sha2::{Sha256, Digest};
+use std::env;
 use std::fs;
 use std::fs::File;
-use std::io::prelude::*;
-use std::collections::HashMap;
-use std::io::Write;
-use clap::{App, Arg, AppSettings};
-use serde::{Serialize, Deserialize};
-use sha2::{Sha256, Digest};
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use common::parser;
+use common::resolve;
+use common::archive;
 use common::ipfs;
+use common::crypto;
+use common::settings;
+use common::store;
+use common::lock;
+use common::trust;
+use common::history;
+use common::Error;
+
+// time a keyring snapshot is valid for unless --valid-until is given, 30 days
+static SNAPSHOT_VALIDITY: u64 = 30 * 24 * 60 * 60;
+
+// Certify keyring entry `entry` with `keypair`
+//
+// The certification is added to the entry of the key if the keyring already has
+// one with the same name, email and validity period, otherwise `entry` is added
+fn update_keyring(keypair: &signature::Ed25519KeyPair, entry: parser::KeyringEntry) -> Result<(), Error> {
+    let mut keyring = parser::get_keyring()?;
+    let signers     = &mut keyring.signers;
+
+    let same = |other: &parser::KeyringEntry| {
+        other.key == entry.key && other.name == entry.name && other.email == entry.email
+            && other.valid_from == entry.valid_from && other.valid_until == entry.valid_until
+    };
 
-fn update_keyring(keypair: &signature::Ed25519KeyPair, name: &str, email: &str, pubkey: &str) {
-    let mut signers = parser::get_signers().unwrap();
-    let sig         = base64::encode(keypair.sign(pubkey.as_bytes()));
+    let index = match signers.iter().position(same) {
+        Some(index) => index,
+        None        => {
+            signers.push(entry);
+            signers.len() - 1
+        }
+    };
 
-    signers.push(parser::KeyringEntry {
-        name:      name.to_string(),
-        email:     email.to_string(),
-        key:       pubkey.to_string(),
-        signature: sig.to_string()
-    });
+    crypto::certify(keypair, &mut signers[index])?;
+    Ok(parser::save_keyring(&keyring)?)
+}
 
-    parser::save_keyring(signers);
+fn check_key(key: &str) -> Result<(), Error> {
+    if base64::decode(key).map(|raw| raw.len() != 32).unwrap_or(true) {
+        return Err(Error::Trust(trust::TrustError::InvalidKey(key.to_string())));
+    }
+
+    Ok(())
 }
 
-async fn update_package(keypair: &signature::Ed25519KeyPair, name: &str, version: &str, path: &str) {
-    let mut files  = parser::get_pkgs(&parser::expand("PKGLIST_bootstrap.toml")).unwrap();
-    let buffer     = parser::get_file_contents(path);
-    let mut sha256 = Sha256::new();
+// Revoke `key` at `since` with `keypair`, which must be the key itself
+// or a trust anchor for the revocation to be accepted (see trust::Web)
+//
+// The revocation is added to the keyring and printed
+fn revoke_key(keypair: &signature::Ed25519KeyPair, key: &str, since: u64, reason: &str) -> Result<(), Error> {
+    check_key(key)?;
+
+    let mut keyring = parser::get_keyring()?;
+    let rev         = crypto::revoke(keypair, key, since, reason)?;
+
+    println!("{}", toml::to_string(&rev).unwrap());
+
+    keyring.revocations.push(rev);
+    Ok(parser::save_keyring(&keyring)?)
+}
+
+// Move the maintainer of the key of `keypair` to the key of `entry`
+//
+// The name and the email of the maintainer are taken from the entry of the
+// current key, and the new entry is added with a rotation statement signed
+// by the current key (see trust::Web)
+fn rotate_key(keypair: &signature::Ed25519KeyPair, mut entry: parser::KeyringEntry) -> Result<(), Error> {
+    check_key(&entry.key)?;
+
+    let mut keyring = parser::get_keyring()?;
+    let current     = base64::encode(keypair.public_key().as_ref());
+
+    let maintainer = match keyring.signers.iter().find(|other| other.key == current) {
+        Some(maintainer) => maintainer,
+        None             => return Err(Error::Trust(trust::TrustError::UnknownKey(current)))
+    };
+
+    entry.name  = maintainer.name.clone();
+    entry.email = maintainer.email.clone();
+
+    crypto::rotate(keypair, &mut entry, crypto::now())?;
+    println!("{} <{}> moved to key {}", entry.name, entry.email, entry.key);
+
+    keyring.signers.push(entry);
+    Ok(parser::save_keyring(&keyring)?)
+}
+
+// Co-sign the published entry of package `name` with `keypair`
+//
+// If `version` is given, it must match the published version so that an
+// entry that was replaced after it was reviewed isn't co-signed
+fn cosign_package(keypair: &signature::Ed25519KeyPair, name: &str, version: Option<&str>) -> Result<(), Error> {
+    let path     = parser::expand("PKGLIST_bootstrap.toml");
+    let mut pkgs = parser::get_pkgs(&path)?;
+    let signer   = crypto::key_id(&base64::encode(keypair.public_key().as_ref())).unwrap();
+
+    let pkg = match pkgs.get_mut(name) {
+        Some(pkg) if version.map(|version| version == pkg.version).unwrap_or(true) => pkg,
+        _ => return Err(Error::IPFS(ipfs::IPFSError::NotFound))
+    };
+
+    if pkg.signer == signer {
+        println!("{} {} is signed by this key already", pkg.name, pkg.version);
+        return Ok(());
+    }
+
+    crypto::cosign(keypair, pkg)?;
+    println!("Co-signed {} {} (sha256 {}), {} co-signature(s)",
+             pkg.name, pkg.version, pkg.sha256, pkg.cosignatures.len());
+
+    Ok(parser::save_pkgs(&path, pkgs)?)
+}
+
+// Allow the maintainer of `key` to publish the packages matching `patterns`
+//
+// A pattern is a package name, or a prefix of package names followed by `*`.
+// The keyring must be signed as a new snapshot for the change to take effect
+fn authorize(key: &str, patterns: &[String]) -> Result<(), Error> {
+    check_key(key)?;
+
+    for pattern in patterns {
+        let name = pattern.strip_suffix('*').unwrap_or(pattern);
+
+        if name.is_empty() || name.contains(|c: char| c == '*' || c.is_whitespace() || c == ',') {
+            return Err(Error::Trust(trust::TrustError::InvalidPattern(pattern.clone())));
+        }
+    }
+
+    let mut keyring = parser::get_keyring()?;
+
+    let index = match keyring.owners.iter().position(|owner| owner.key == key) {
+        Some(index) => index,
+        None        => {
+            keyring.owners.push(parser::Ownership { key: key.to_string(), packages: Vec::new() });
+            keyring.owners.len() - 1
+        }
+    };
+
+    let owner = &mut keyring.owners[index];
 
-    sha256.update(&buffer);
-    let digest = format!("{:x}", sha256.finalize());
-    let sig = base64::encode(keypair.sign(digest.as_bytes()));
-
-    match ipfs::upload(path).await {
-        Ok(ipfs) => {
-            files.insert(name.to_string(), parser::PkgInfo {
-                name:      name.to_string(),
-                version:   version.to_string(),
-                sha256:    digest,
-                ipfs:      ipfs,
-                signature: sig.to_string()
-            });
-
-            parser::save_pkgs(&parser::expand("PKGLIST_bootstrap.toml"), files);
-        },
-        Err(err) => {
-            println!("Failed to upload {} to IPFS", name)
+    for pattern in patterns {
+        if !owner.packages.contains(pattern) {
+            owner.packages.push(pattern.clone());
         }
     }
+
+    println!("{} may publish {}", key, owner.packages.join(", "));
+    Ok(parser::save_keyring(&keyring)?)
+}
+
+// Sign the keyring as snapshot `serial` expiring at `expires` with `keypair`,
+// which must be a trust anchor for the snapshot to be accepted (see trust::check_snapshot)
+//
+// The serial defaults to the serial of the previous snapshot plus one
+fn sign_keyring(keypair: &signature::Ed25519KeyPair, serial: Option<u64>, expires: u64) -> Result<(), Error> {
+    let mut keyring = parser::get_keyring()?;
+
+    keyring.serial  = serial.unwrap_or(keyring.serial + 1);
+    keyring.expires = expires;
+
+    crypto::sign_keyring(keypair, &mut keyring)?;
+    println!("Signed keyring snapshot {}, expires {}", keyring.serial, history::format_time(keyring.expires));
+
+    Ok(parser::save_keyring(&keyring)?)
+}
+
+// sha256 digest and size of file `path`, read in chunks
+fn hash_file(path: &Path) -> Result<(String, u64), Error> {
+    let mut sha256 = Sha256::new();
+
+    match File::open(path).and_then(|mut file| io::copy(&mut file, &mut sha256)) {
+        Ok(size) => Ok((format!("{:x}", sha256.finalize()), size)),
+        Err(_)   => Err(Error::Parser(parser::ParserError::ReadError(path.display().to_string())))
+    }
+}
+
+// Upload `archive`, then sign `pkg` and save it to the package list
+//
+// Name, version and the package relations of `pkg` must be set by the caller
+async fn update_package(
+    store: &dyn store::ContentStore,
+    keypair: &signature::Ed25519KeyPair,
+    mut pkg: parser::PkgInfo,
+    archive: &Path
+) -> Result<(), Error> {
+    let mut files = parser::get_pkgs(&parser::expand("PKGLIST_bootstrap.toml"))?;
+
+    resolve::check_relations(&pkg)?;
+
+    let (sha256, size) = hash_file(archive)?;
+
+    pkg.sha256 = sha256;
+    pkg.size   = size;
+    pkg.ipfs   = ipfs::upload(store, archive).await?;
+
+    crypto::sign_pkginfo(keypair, &mut pkg)?;
+
+    files.insert(pkg.name.clone(), pkg);
+    Ok(parser::save_pkgs(&parser::expand("PKGLIST_bootstrap.toml"), files)?)
 }
 
 fn show_usage() {
@@ -71,13 +233,227 @@ fn show_usage() {
              \t\t--update-package\n\
              \t\t--name clang \n\
              \t\t--version \"11.1.0\" \n\
-             \t\t--path /usr/bin/clang\n\
+             \t\t--depends \"llvm = 11.1.0\" \n\
+             \t\t--staging ./clang-root\n\
+             \t\t--pkcs8 /home/rficu/.config/pkgman/pkcs8\n");
+
+    println!("Key rotation:");
+    println!("\t./pkgmain \n\
+             \t\t--rotate-key \"l88K/HuJh4KvbfzK6ar1X3cB5qscquUNgnNpMaCOGrA=\" \n\
+             \t\t--valid-until 1893456000 \n\
+             \t\t--pkcs8 /home/rficu/.config/pkgman/pkcs8\n");
+
+    println!("Package co-signing:");
+    println!("\t./pkgmain \n\
+             \t\t--cosign\n\
+             \t\t--name clang \n\
+             \t\t--version \"11.1.0\" \n\
+             \t\t--pkcs8 /home/rficu/.config/pkgman/pkcs8\n");
+
+    println!("Package ownership:");
+    println!("\t./pkgmain \n\
+             \t\t--authorize \"3c2PgNisX4vOumXAYVETS1aDKLHYEuhKSo7i1xnwr2Y=\" \n\
+             \t\t--package clang \n\
+             \t\t--package \"llvm-*\"\n");
+
+    println!("Keyring snapshot:");
+    println!("\t./pkgmain \n\
+             \t\t--sign-keyring\n\
+             \t\t--pkcs8 /home/rficu/.config/pkgman/pkcs8\n");
+
+    println!("Key revocation:");
+    println!("\t./pkgmain \n\
+             \t\t--revoke-key \"3c2PgNisX4vOumXAYVETS1aDKLHYEuhKSo7i1xnwr2Y=\" \n\
+             \t\t--reason \"key compromised\" \n\
              \t\t--pkcs8 /home/rficu/.config/pkgman/pkcs8");
 }
 
+// Build the package archive from the staging directory given with --staging,
+// or check the prebuilt archive given with --path
+//
+// The archive is built to the file given with --output, or to a temporary
+// file if there's none. Returns the path of the archive and whether it's a
+// temporary file that should be removed once the archive has been uploaded
+fn package_archive(matches: &ArgMatches, name: &str, version: &str) -> Result<(PathBuf, bool), Error> {
+    if let Some(staging) = matches.value_of("staging") {
+        let (path, temporary) = match matches.value_of("output") {
+            Some(output) => (PathBuf::from(output), false),
+            None         => (env::temp_dir().join(format!("pkgmain-{}.pkg.tar.zst", process::id())), true)
+        };
+
+        let write_error = || Error::Archive(archive::ArchiveError::WriteError(path.display().to_string()));
+
+        let file = match File::create(&path) {
+            Ok(file) => file,
+            Err(_)   => return Err(write_error())
+        };
+
+        let res = archive::write(staging, name, version, BufWriter::new(file))
+            .map_err(Error::Archive)
+            .and_then(|writer| writer.into_inner().map_err(|_| write_error()))
+            .and_then(|file| file.sync_all().map_err(|_| write_error()));
+
+        if let Err(err) = res {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+
+        return Ok((path, temporary));
+    }
+
+    let path = PathBuf::from(matches.value_of("path").unwrap());
+
+    if !path.is_file() {
+        return Err(Error::Parser(parser::ParserError::NotFoundError(path.display().to_string())));
+    }
+
+    archive::open(&path, name, version)?;
+
+    Ok((path, false))
+}
+
+// values of a package relation option that may be given multiple times
+fn relation_values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    match matches.values_of(name) {
+        Some(values) => values.map(|val| val.to_string()).collect(),
+        None         => Vec::new()
+    }
+}
+
+// value of a time option in seconds since the Unix epoch, `default` if the
+// option isn't given or None if it's not a valid time
+fn time_arg(matches: &ArgMatches, name: &str, default: u64) -> Option<u64> {
+    match matches.value_of(name) {
+        Some(val) => val.parse::<u64>().ok(),
+        None      => Some(default)
+    }
+}
+
 // read a PKCS 8-formatted key pair from a file
-fn read_keypair(path: &str) -> signature::Ed25519KeyPair {
-    return signature::Ed25519KeyPair::from_pkcs8(&parser::get_file_contents(path)).unwrap();
+fn read_keypair(path: &str) -> Result<signature::Ed25519KeyPair, Error> {
+    match signature::Ed25519KeyPair::from_pkcs8(&parser::get_file_contents(path)?) {
+        Ok(keypair) => Ok(keypair),
+        Err(_)      => Err(Error::Parser(parser::ParserError::SyntaxError {
+            path:    path.to_string(),
+            line:    0,
+            column:  0,
+            message: "invalid PKCS 8 key pair".to_string()
+        }))
+    }
+}
+
+async fn run(matches: &ArgMatches<'_>) -> Result<(), Error> {
+    // the ownership map is signed with the keyring snapshot
+    if let Some(key) = matches.value_of("authorize") {
+        let _lock = lock::acquire(matches.is_present("wait"))?;
+
+        return authorize(key, &relation_values(matches, "package"));
+    }
+
+    let key_pair = read_keypair(matches.value_of("pkcs8").unwrap())?;
+    let _lock    = lock::acquire(matches.is_present("wait"))?;
+
+    if matches.is_present("update-keyring") || matches.is_present("rotate-key") {
+        let (valid_from, valid_until) = match (time_arg(matches, "valid-from", 0), time_arg(matches, "valid-until", 0)) {
+            (Some(from), Some(until)) if until == 0 || until > from => (from, until),
+            _ => {
+                println!("Invalid validity period, expected seconds since the Unix epoch");
+                return Ok(());
+            }
+        };
+
+        // the new key of a rotation keeps the name and email of the maintainer, see rotate_key()
+        let (name, email) = match matches.is_present("rotate-key") {
+            true  => ("", ""),
+            false => (matches.value_of("name").unwrap(), matches.value_of("email").unwrap())
+        };
+
+        let entry = parser::KeyringEntry {
+            name:           name.to_string(),
+            email:          email.to_string(),
+            key:            matches.value_of("public-key").or_else(|| matches.value_of("rotate-key")).unwrap().to_string(),
+            signature:      String::new(),
+            valid_from,
+            valid_until,
+            certifications: Vec::new(),
+            rotation:       None
+        };
+
+        return match matches.is_present("rotate-key") {
+            true  => rotate_key(&key_pair, entry),
+            false => update_keyring(&key_pair, entry)
+        };
+    }
+
+    if matches.is_present("sign-keyring") {
+        let serial = match matches.value_of("serial").map(|serial| serial.parse::<u64>()) {
+            Some(Ok(serial)) => Some(serial),
+            Some(Err(_))     => {
+                println!("Invalid serial of the keyring snapshot");
+                return Ok(());
+            },
+            None => None
+        };
+
+        let expires = match time_arg(matches, "valid-until", crypto::now() + SNAPSHOT_VALIDITY) {
+            Some(expires) => expires,
+            None          => {
+                println!("Invalid expiry time, expected seconds since the Unix epoch");
+                return Ok(());
+            }
+        };
+
+        return sign_keyring(&key_pair, serial, expires);
+    }
+
+    if let Some(key) = matches.value_of("revoke-key") {
+        let since = match time_arg(matches, "since", crypto::now()) {
+            Some(since) => since,
+            None        => {
+                println!("Invalid revocation time, expected seconds since the Unix epoch");
+                return Ok(());
+            }
+        };
+
+        return revoke_key(&key_pair, key, since, matches.value_of("reason").unwrap_or(""));
+    }
+
+    if matches.is_present("cosign") {
+        return cosign_package(&key_pair, matches.value_of("name").unwrap(), matches.value_of("version"));
+    }
+
+    if matches.is_present("update-package") {
+        let store   = store::from_settings(&settings::get_settings()?);
+        let name    = matches.value_of("name").unwrap();
+        let version = matches.value_of("version").unwrap();
+
+        let pkg = parser::PkgInfo {
+            name:         name.to_string(),
+            version:      version.to_string(),
+            sha256:       String::new(),
+            ipfs:         String::new(),
+            signature:    String::new(),
+            signer:       String::new(),
+            timestamp:    0,
+            size:         0,
+            depends:      relation_values(matches, "depends"),
+            conflicts:    relation_values(matches, "conflicts"),
+            provides:     relation_values(matches, "provides"),
+            replaces:     relation_values(matches, "replaces"),
+            cosignatures: Vec::new()
+        };
+
+        let (archive, temporary) = package_archive(matches, name, version)?;
+        let res = update_package(store.as_ref(), &key_pair, pkg, &archive).await;
+
+        if temporary {
+            let _ = fs::remove_file(&archive);
+        }
+
+        return res;
+    }
+
+    Ok(())
 }
 
 #[actix_rt::main]
@@ -90,15 +466,75 @@ async fn main() {
                  .short("k")
                  .long("update-keyring")
                  .takes_value(false)
+                 .requires_all(&["public-key", "name", "email"])
                  .help("Update keyring, mutually exclusive with --update-package"))
         .arg(Arg::with_name("update-package")
                  .short("p")
                  .long("update-package")
                  .takes_value(false)
+                 .requires_all(&["name", "version", "archive"])
                  .help("Update package list, mutually exclusive with --update-keyring"))
+        .arg(Arg::with_name("revoke-key")
+                 .long("revoke-key")
+                 .takes_value(true)
+                 .conflicts_with_all(&["update-keyring", "update-package"])
+                 .help("Revoke a base64-encoded public key, signing the revocation with --pkcs8"))
+        .arg(Arg::with_name("reason")
+                 .long("reason")
+                 .takes_value(true)
+                 .requires("revoke-key")
+                 .help("Reason of the revocation"))
+        .arg(Arg::with_name("since")
+                 .long("since")
+                 .takes_value(true)
+                 .requires("revoke-key")
+                 .help("Time the key was revoked or compromised at in seconds since the Unix epoch, now by default"))
+        .arg(Arg::with_name("cosign")
+                 .long("cosign")
+                 .takes_value(false)
+                 .requires("name")
+                 .conflicts_with_all(&["update-keyring", "update-package"])
+                 .help("Add a signature to the published entry of the package given with --name"))
+        .arg(Arg::with_name("sign-keyring")
+                 .long("sign-keyring")
+                 .takes_value(false)
+                 .conflicts_with_all(&["update-keyring", "update-package", "revoke-key", "rotate-key"])
+                 .help("Sign the keyring as a new snapshot with the --pkcs8 key pair of a trust anchor"))
+        .arg(Arg::with_name("serial")
+                 .long("serial")
+                 .takes_value(true)
+                 .requires("sign-keyring")
+                 .help("Serial of the keyring snapshot, the serial of the previous snapshot plus one by default"))
+        .arg(Arg::with_name("authorize")
+                 .long("authorize")
+                 .takes_value(true)
+                 .requires("package")
+                 .conflicts_with_all(&["update-keyring", "update-package", "revoke-key", "sign-keyring"])
+                 .help("Allow a base64-encoded public key to publish the packages given with --package"))
+        .arg(Arg::with_name("package")
+                 .long("package")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .requires("authorize")
+                 .help("Package name, or a prefix of package names followed by \"*\" (may be repeated)"))
+        .arg(Arg::with_name("rotate-key")
+                 .long("rotate-key")
+                 .takes_value(true)
+                 .conflicts_with_all(&["update-keyring", "update-package", "revoke-key"])
+                 .help("Move the maintainer of the --pkcs8 key pair to a new base64-encoded public key"))
+        .arg(Arg::with_name("valid-from")
+                 .long("valid-from")
+                 .takes_value(true)
+                 .help("Start of the validity period of the key in seconds since the Unix epoch"))
+        .arg(Arg::with_name("valid-until")
+                 .long("valid-until")
+                 .takes_value(true)
+                 .help("End of the validity period of the key or the keyring snapshot in seconds since the Unix epoch"))
         .arg(Arg::with_name("email")
                  .long("email")
                  .takes_value(true)
+                 .empty_values(false)
                  .help("Email of the maintainer"))
         .arg(Arg::with_name("public-key")
                  .long("public-key")
@@ -107,6 +543,7 @@ async fn main() {
         .arg(Arg::with_name("name")
                  .long("name")
                  .takes_value(true)
+                 .empty_values(false)
                  .help("Name of the maintainer/package"))
         .arg(Arg::with_name("version")
                  .long("version")
@@ -115,43 +552,64 @@ async fn main() {
         .arg(Arg::with_name("path")
                  .long("path")
                  .takes_value(true)
-                 .help("Path to the binary of the package"))
+                 .help("Path to the package archive, mutually exclusive with --staging"))
+        .arg(Arg::with_name("staging")
+                 .long("staging")
+                 .takes_value(true)
+                 .help("Build the package archive from a staging directory"))
+        .arg(Arg::with_name("output")
+                 .long("output")
+                 .takes_value(true)
+                 .requires("staging")
+                 .help("Also save the archive built from --staging to a file"))
+        .arg(Arg::with_name("depends")
+                 .long("depends")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Package this package depends on, e.g., \"glibc >= 2.31\" (may be repeated)"))
+        .arg(Arg::with_name("conflicts")
+                 .long("conflicts")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Package that can't be installed together with this package (may be repeated)"))
+        .arg(Arg::with_name("provides")
+                 .long("provides")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Virtual package provided by this package, e.g., \"cc = 11\" (may be repeated)"))
+        .arg(Arg::with_name("replaces")
+                 .long("replaces")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Package that is removed when this package is installed (may be repeated)"))
         .arg(Arg::with_name("pkcs8")
                  .long("pkcs8")
                  .takes_value(true)
+                 .required_unless_one(&["usage", "authorize"])
                  .help("Full path to the PKCS 8-formatted keypair"))
         .arg(Arg::with_name("usage")
                  .short("u")
                  .long("usage")
                  .takes_value(false)
                  .help("Show usage for the tool"))
+        .arg(Arg::with_name("wait")
+                 .long("wait")
+                 .takes_value(false)
+                 .help("Wait for another running pkgman to finish instead of failing"))
+        .group(ArgGroup::with_name("archive")
+                 .args(&["path", "staging"]))
         .get_matches();
 
-    if (matches.is_present("usage")) {
+    if matches.is_present("usage") {
         show_usage();
         return;
     }
 
-    let key_pair = read_keypair(matches.value_of("pkcs8").unwrap());
-
-    if matches.is_present("update-keyring") {
-        update_keyring(
-            &key_pair,
-            matches.value_of("name").unwrap(),
-            matches.value_of("email").unwrap(),
-            matches.value_of("public-key").unwrap()
-        );
-        return;
-    }
-
-    if matches.is_present("update-package") {
-        update_package(
-            &key_pair,
-            matches.value_of("name").unwrap(),
-            matches.value_of("version").unwrap(),
-            matches.value_of("path").unwrap()
-        ).await;
-
-        return;
+    if let Err(err) = run(&matches).await {
+        println!("Error occurred: {}", err);
     }
 }
@@ -11,46 +11,211 @@ use common::daemon;
 use common::network;
 use common::parser;
 use common::ipfs;
+use common::settings;
+use common::store;
+use common::transport;
+use common::lock;
+use common::install;
+use common::transaction;
+use common::history;
+use common::cache;
+use common::trust;
+use common::crypto;
+use common::Error;
 
-async fn update() {
-    match network::update().await {
+async fn update(
+    store: &dyn store::ContentStore,
+    transport: &dyn transport::Transport,
+    allow_downgrade: bool,
+    overwrite: bool
+) {
+    match network::update(store, transport, allow_downgrade, overwrite).await {
         Ok(_)    => (),
-        Err(err) => println!("Error occurred: {:#?}", err)
+        Err(err) => println!("Error occurred: {}", err)
     };
 }
 
-async fn download(name: &str) {
-    match network::download(name).await {
+async fn download(
+    store: &dyn store::ContentStore,
+    transport: &dyn transport::Transport,
+    name: &str,
+    allow_downgrade: bool,
+    overwrite: bool
+) {
+    match network::download(store, transport, name, allow_downgrade, overwrite).await {
         Ok(_) => {
             println!("Package {} downloaded!", name);
         },
         Err(err) => match err {
-            ipfs::IPFSError::NotFound => {
+            Error::IPFS(ipfs::IPFSError::NotFound) => {
                 println!("Package {} not found on the network!", name);
-                return;
             },
-            ipfs::IPFSError::AlreadyExists => {
+            Error::IPFS(ipfs::IPFSError::AlreadyExists) => {
                 println!("{} is up to date!", name);
-                return;
+            },
+            Error::IPFS(ipfs::IPFSError::NewerExists) => {
+                println!("A newer version of {} is already installed, use --allow-downgrade to replace it", name);
             },
             _ => {
-                println!("Error occurred: {:#?}", err);
-                return;
+                println!("Error occurred: {}", err);
+            }
+        }
+    };
+}
+
+fn remove(name: &str, cascade: bool) {
+    match install::remove_packages(name, cascade) {
+        Ok(removed) => {
+            for pkg in removed {
+                println!("Package {} removed!", pkg);
+            }
+        },
+        Err(Error::Install(err @ install::InstallError::RequiredBy { .. })) => {
+            println!("Error occurred: {}, use --cascade to remove them as well", err);
+        },
+        Err(err) => println!("Error occurred: {}", err)
+    };
+}
+
+fn autoremove() {
+    match install::autoremove() {
+        Ok(removed) if removed.is_empty() => println!("No orphaned packages"),
+        Ok(removed) => {
+            for pkg in removed {
+                println!("Package {} removed!", pkg);
             }
+        },
+        Err(err) => println!("Error occurred: {}", err)
+    };
+}
+
+fn list_generations() {
+    let generations = match parser::get_generations() {
+        Ok(generations) => generations,
+        Err(err) => {
+            println!("Error occurred: {}", err);
+            return;
+        }
+    };
+
+    if generations.is_empty() {
+        println!("No generations recorded");
+        return;
+    }
+
+    let current = generations.last().map(|generation| generation.number);
+
+    for generation in generations.iter() {
+        println!("{:>4}  {}  {} package(s){}",
+                 generation.number,
+                 history::format_time(generation.timestamp),
+                 generation.packages.len(),
+                 if Some(generation.number) == current { "  (current)" } else { "" });
+
+        for pkg in generation.packages.iter() {
+            println!("      {} {}  {}", pkg.name, pkg.version, pkg.ipfs);
         }
+    }
+}
+
+async fn rollback(store: &dyn store::ContentStore, number: Option<&str>, overwrite: bool) {
+    let number = match number.map(|num| num.parse::<u64>()) {
+        Some(Ok(num)) => Some(num),
+        Some(Err(_))  => {
+            println!("Error occurred: invalid generation number");
+            return;
+        },
+        None => None
+    };
+
+    match history::rollback(store, number, overwrite).await {
+        Ok(num)  => println!("Rolled back to generation {}!", num),
+        Err(err) => println!("Error occurred: {}", err)
     };
 }
 
-async fn query(name: &str) {
-    let pkginfo = match network::query(name).await {
+fn cache_clean(keep_installed: bool) {
+    match cache::clean(keep_installed) {
+        Ok((count, size)) => println!("Removed {} cached package(s), {} KiB freed", count, size / 1024),
+        Err(err)          => println!("Error occurred: {}", err)
+    };
+}
+
+// Print the trust paths of the keys of maintainer `name`, which is a name or a key id
+fn explain_trust(name: &str) -> Result<(), Error> {
+    let web = trust::load()?;
+    let matches = |entry_name: &str, key: &str| entry_name == name || crypto::key_id(key).as_deref() == Some(name);
+    let mut keys: Vec<&String> = Vec::new();
+
+    for anchor in web.anchors().iter().filter(|anchor| matches(&anchor.name, &anchor.key)) {
+        println!("{} ({}) is a trust anchor", anchor.name, anchor.key);
+        keys.push(&anchor.key);
+    }
+
+    for entry in web.entries().iter().filter(|entry| matches(&entry.name, &entry.key)) {
+        if keys.contains(&&entry.key) {
+            continue;
+        }
+
+        let paths = web.paths(&entry.key);
+
+        println!("{} <{}> ({}) is {}: {} independent path(s) of at most {} certification(s), {} required",
+                 entry.name, entry.email, entry.key,
+                 if web.trusted(&entry.key) { "trusted" } else { "not trusted" },
+                 paths.len(), web.max_depth(), web.min_paths());
+
+        for path in paths {
+            let names: Vec<String> = path.iter().map(|key| web.name(key)).collect();
+            println!("  {} (anchor) -> {}", names[0], names[1..].join(" -> "));
+        }
+
+        for (from, until) in web.validity(&entry.key) {
+            match (from, until) {
+                (0, 0)    => (),
+                (from, 0) => println!("  valid from {}", history::format_time(from)),
+                (from, until) => println!("  valid from {} until {}", history::format_time(from), history::format_time(until))
+            }
+        }
+
+        let packages = web.packages(&entry.key);
+
+        match packages.is_empty() {
+            true  => println!("  may not publish any package"),
+            false => println!("  may publish {}", packages.join(", "))
+        };
+
+        if let Some(rotation) = &entry.rotation {
+            println!("  rotated from {} at {}", web.name(&rotation.key), history::format_time(rotation.timestamp));
+        }
+
+        if let Some(time) = web.rotated(&entry.key) {
+            println!("  rotated to another key since {}", history::format_time(time));
+        }
+
+        if let Some(time) = web.revoked(&entry.key) {
+            println!("  revoked since {}", history::format_time(time));
+        }
+
+        keys.push(&entry.key);
+    }
+
+    if keys.is_empty() {
+        println!("No key named {} in the keyring", name);
+    }
+
+    Ok(())
+}
+
+async fn query(transport: &dyn transport::Transport, name: &str) {
+    let pkginfo = match network::query(transport, name).await {
         Ok(info) => info,
         Err(err) => match err {
-            ipfs::IPFSError::NotFound => {
+            Error::IPFS(ipfs::IPFSError::NotFound) => {
                 println!("Package {} not found on the network", name);
                 return;
             },
             _ => {
-                println!("Error occurred: {:#?}", err);
+                println!("Error occurred: {}", err);
                 return;
             }
         }
@@ -61,33 +226,67 @@ async fn query(name: &str) {
              sha256:  {}\n\
              ipfs:    {}",
              pkginfo.name, pkginfo.version, pkginfo.sha256, pkginfo.ipfs);
+
+    let relations = [
+        ("depends",   &pkginfo.depends),
+        ("conflicts", &pkginfo.conflicts),
+        ("provides",  &pkginfo.provides),
+        ("replaces",  &pkginfo.replaces),
+    ];
+
+    for (name, values) in relations.iter() {
+        if !values.is_empty() {
+            println!("{}: {}", name, values.join(", "));
+        }
+    }
 }
 
-async fn update_keyring() {
-    match network::update_keyring().await {
+async fn update_keyring(transport: &dyn transport::Transport) {
+    match network::update_keyring(transport).await {
         Ok(_)    => println!("Keyring updated!"),
-        Err(err) => println!("Error occurred: {:#?}", err)
+        Err(err) => println!("Error occurred: {}", err)
     };
 }
 
-fn init() {
+// Create the configuration directory, an empty keyring and an empty package list
+// and add `root_keys` to the trust anchors of pkgman.toml
+fn init(root_keys: &[&str]) -> Result<(), Error> {
 
-    let home    = std::env::var("HOME").unwrap();
-    let base    = PathBuf::from(format!("{}/.config/pkgman/", home));
-    let config  = PathBuf::from(format!("{}/.config/pkgman/PKGLIST.toml", home));
-    let keyring = PathBuf::from(format!("{}/.config/pkgman/KEYRING.toml", home));
+    let base    = PathBuf::from(parser::expand(""));
+    let config  = PathBuf::from(parser::expand("PKGLIST.toml"));
+    let keyring = PathBuf::from(parser::expand("KEYRING.toml"));
 
-    if !Path::new(&base).exists() {
-        fs::create_dir(base).unwrap();
+    if !Path::new(&base).exists() && fs::create_dir_all(&base).is_err() {
+        return Err(Error::Parser(parser::ParserError::WriteError(base.display().to_string())));
     }
 
     if !Path::new(&keyring).exists() {
-        parser::save_keyring_default();
+        parser::save_keyring(&parser::KeyringConfig::default())?;
     }
 
-    if !Path::new(&config).exists() {
-        File::create(&config).unwrap();
+    if !Path::new(&config).exists() && File::create(&config).is_err() {
+        return Err(Error::Parser(parser::ParserError::WriteError(config.display().to_string())));
     }
+
+    let configured = settings::get_settings()?.trust_anchors;
+    let mut anchors: Vec<settings::TrustAnchor> = Vec::new();
+
+    for key in root_keys {
+        let anchor = trust::anchor(key)?;
+
+        if configured.iter().chain(anchors.iter()).all(|other| other.key != anchor.key) {
+            println!("Trusting root key {} ({})", anchor.name, anchor.key);
+            anchors.push(anchor);
+        }
+    }
+
+    if !anchors.is_empty() {
+        parser::save_trust_anchors(&anchors)?;
+    } else if configured.is_empty() {
+        println!("No trust anchors configured, packages can't be verified until a root key is added with --root-key");
+    }
+
+    Ok(())
 }
 
 #[actix_rt::main]
@@ -101,6 +300,10 @@ async fn main() {
                  .long("daemon")
                  .takes_value(false)
                  .help("Start pkgman as a daemon"))
+        .arg(Arg::with_name("broker")
+                 .long("broker")
+                 .takes_value(true)
+                 .help("Run a local pubsub broker on the given Unix socket"))
         .arg(Arg::with_name("update")
                  .short("u")
                  .long("update")
@@ -111,6 +314,47 @@ async fn main() {
                  .long("download")
                  .takes_value(true)
                  .help("Download package"))
+        .arg(Arg::with_name("remove")
+                 .short("r")
+                 .long("remove")
+                 .takes_value(true)
+                 .help("Remove package"))
+        .arg(Arg::with_name("cascade")
+                 .long("cascade")
+                 .takes_value(false)
+                 .requires("remove")
+                 .help("Also remove the packages that depend on the removed package"))
+        .arg(Arg::with_name("autoremove")
+                 .long("autoremove")
+                 .takes_value(false)
+                 .help("Remove packages that were installed as dependencies and are no longer needed"))
+        .arg(Arg::with_name("list-generations")
+                 .long("list-generations")
+                 .takes_value(false)
+                 .help("List the recorded generations of installed packages"))
+        .arg(Arg::with_name("rollback")
+                 .long("rollback")
+                 .takes_value(true)
+                 .min_values(0)
+                 .value_name("N")
+                 .help("Return to generation N, or to the previous generation"))
+        .arg(Arg::with_name("cache-clean")
+                 .long("cache-clean")
+                 .takes_value(false)
+                 .help("Remove downloaded packages from ~/.cache/pkgman/blobs"))
+        .arg(Arg::with_name("keep-installed")
+                 .long("keep-installed")
+                 .takes_value(false)
+                 .requires("cache-clean")
+                 .help("Keep the cached packages that are currently installed"))
+        .arg(Arg::with_name("allow-downgrade")
+                 .long("allow-downgrade")
+                 .takes_value(false)
+                 .help("Allow --download and --update to replace a package with an older version"))
+        .arg(Arg::with_name("overwrite")
+                 .long("overwrite")
+                 .takes_value(false)
+                 .help("Allow --download, --update and --rollback to replace files that no package installed"))
         .arg(Arg::with_name("query")
                  .short("q")
                  .long("query")
@@ -121,24 +365,117 @@ async fn main() {
                  .long("init")
                  .takes_value(false)
                  .help("Create ~/.config/pkgman/{keyring/,PKGLIST.toml} files"))
+        .arg(Arg::with_name("root-key")
+                 .long("root-key")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .requires("init")
+                 .help("Base64-encoded public key to trust as a root of the keyring (may be repeated)"))
         .arg(Arg::with_name("update-keyring")
                  .short("k")
                  .long("update-keyring")
                  .takes_value(false)
                  .help("Query the information of all maintainers from the network"))
+        .arg(Arg::with_name("explain-trust")
+                 .long("explain-trust")
+                 .takes_value(true)
+                 .value_name("NAME")
+                 .help("Show the certification paths from the trust anchors to a maintainer key"))
+        .arg(Arg::with_name("wait")
+                 .long("wait")
+                 .takes_value(false)
+                 .help("Wait for another running pkgman to finish instead of failing"))
         .get_matches();
 
-    if matches.is_present("daemon") {
-        daemon::daemon().await;
+    let settings = match settings::get_settings() {
+        Ok(settings) => settings,
+        Err(err) => {
+            println!("Error occurred: {}", err);
+            return;
+        }
+    };
+
+    let store     = store::from_settings(&settings);
+    let transport = transport::from_settings(&settings);
+
+    // commands that modify PKGLIST.toml, KEYRING.toml or the cache must not run concurrently
+    let _lock = if matches.is_present("update")
+        || matches.is_present("download")
+        || matches.is_present("remove")
+        || matches.is_present("autoremove")
+        || matches.is_present("rollback")
+        || matches.is_present("cache-clean")
+        || matches.is_present("update-keyring")
+    {
+        let lock = match lock::acquire(matches.is_present("wait")) {
+            Ok(lock) => lock,
+            Err(err) => {
+                println!("Error occurred: {}", err);
+                return;
+            }
+        };
+
+        // roll back the changes of a previous pkgman that was interrupted
+        if let Err(err) = transaction::recover() {
+            println!("Error occurred: {}", err);
+            return;
+        }
+
+        Some(lock)
+    } else {
+        None
+    };
+
+    if matches.is_present("broker") {
+        if let Err(err) = transport::unix_broker(matches.value_of("broker").unwrap()).await {
+            println!("Error occurred: {}", err);
+        }
+    } else if matches.is_present("daemon") {
+        if let Err(err) = daemon::daemon(store.as_ref(), transport.as_ref()).await {
+            println!("Error occurred: {}", err);
+        }
     } else if matches.is_present("update") {
-        update().await;
+        update(
+            store.as_ref(),
+            transport.as_ref(),
+            matches.is_present("allow-downgrade"),
+            matches.is_present("overwrite")
+        ).await;
     } else if matches.is_present("download") {
-        download(matches.value_of("download").unwrap()).await;
+        download(
+            store.as_ref(),
+            transport.as_ref(),
+            matches.value_of("download").unwrap(),
+            matches.is_present("allow-downgrade"),
+            matches.is_present("overwrite")
+        ).await;
+    } else if matches.is_present("remove") {
+        remove(matches.value_of("remove").unwrap(), matches.is_present("cascade"));
+    } else if matches.is_present("autoremove") {
+        autoremove();
+    } else if matches.is_present("list-generations") {
+        list_generations();
+    } else if matches.is_present("rollback") {
+        rollback(store.as_ref(), matches.value_of("rollback"), matches.is_present("overwrite")).await;
+    } else if matches.is_present("cache-clean") {
+        cache_clean(matches.is_present("keep-installed"));
     } else if matches.is_present("update-keyring") {
-        update_keyring().await;
+        update_keyring(transport.as_ref()).await;
+    } else if matches.is_present("explain-trust") {
+        if let Err(err) = explain_trust(matches.value_of("explain-trust").unwrap()) {
+            println!("Error occurred: {}", err);
+        }
     } else if matches.is_present("init") {
-        init();
+        let root_keys: Vec<&str> = matches.values_of("root-key").map(|keys| keys.collect()).unwrap_or_default();
+
+        if let Err(err) = init(&root_keys) {
+            println!("Error occurred: {}", err);
+        }
+    } else if let Some(name) = matches.value_of("query") {
+        query(transport.as_ref(), name).await;
     } else {
-        query(matches.value_of("query").unwrap()).await;
+        // only options were given, e.g., --allow-downgrade or --wait
+        println!("No command given, run with --help for usage");
     }
 }
@@ -0,0 +1,126 @@
+use sha2::{Sha256, Digest};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::Error;
+use crate::parser;
+
+// Cache of downloaded packages, stored in ~/.cache/pkgman/blobs/<cid>
+//
+// Cached packages are never trusted as such: their contents are hashed and
+// compared to the signed sha256 digest every time they are used, and entries
+// that don't match are removed. The modification time of an entry is updated
+// whenever it's used, so that the least recently used entries are evicted
+// first once the cache grows past `cache_size` (see settings.rs)
+
+pub fn dir() -> PathBuf {
+    parser::home().join(".cache/pkgman/blobs")
+}
+
+// Path of the cache entry of `cid`, or None if `cid` can't be used as a file name
+pub fn blob_path(cid: &str) -> Option<PathBuf> {
+    if cid.is_empty() || cid.starts_with('.') || !cid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+
+    Some(dir().join(cid))
+}
+
+// Path of the cached contents of `pkg` if they match its signed sha256 digest and size
+pub fn lookup(pkg: &parser::PkgInfo) -> Option<PathBuf> {
+    let path       = blob_path(&pkg.ipfs)?;
+    let mut file   = File::open(&path).ok()?;
+    let mut sha256 = Sha256::new();
+
+    let size  = io::copy(&mut file, &mut sha256).ok()?;
+    let valid = format!("{:x}", sha256.finalize()) == pkg.sha256 && (pkg.size == 0 || size == pkg.size);
+
+    if !valid {
+        println!("Removing corrupted cache entry {}", path.display());
+        let _ = fs::remove_file(&path);
+        return None;
+    }
+
+    let _ = file.set_modified(SystemTime::now());
+    Some(path)
+}
+
+struct Entry {
+    path:     PathBuf,
+    size:     u64,
+    modified: SystemTime
+}
+
+// entries of the cache, including unfinished downloads if `partial` is set
+fn entries(partial: bool) -> Vec<Entry> {
+    let dir = match fs::read_dir(dir()) {
+        Ok(dir) => dir,
+        Err(_)  => return Vec::new()
+    };
+
+    dir.flatten()
+        .filter(|entry| partial || !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+
+            Some(Entry {
+                path:     entry.path(),
+                size:     meta.len(),
+                modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH)
+            })
+        })
+        .collect()
+}
+
+// Remove the least recently used entries until the cache is at most `limit` bytes
+//
+// `keep` is never removed, so that a package larger than the limit can still be installed
+pub fn evict(limit: u64, keep: &Path) {
+    let mut entries = entries(false);
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+
+    entries.sort_by_key(|entry| entry.modified);
+
+    for entry in entries {
+        if total <= limit {
+            break;
+        }
+
+        if entry.path != *keep && fs::remove_file(&entry.path).is_ok() {
+            total -= entry.size;
+        }
+    }
+}
+
+// Remove all cache entries, or only the ones that don't belong to an
+// installed package if `keep_installed` is set
+//
+// Must be called with the lock of the package database held, as the
+// unfinished downloads of other pkgman processes are removed as well
+//
+// Returns the number of removed entries and their total size
+pub fn clean(keep_installed: bool) -> Result<(usize, u64), Error> {
+    let mut keep: Vec<PathBuf> = Vec::new();
+
+    if keep_installed {
+        keep = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?
+            .values()
+            .filter_map(|pkg| blob_path(&pkg.ipfs))
+            .collect();
+    }
+
+    let mut count = 0;
+    let mut size  = 0;
+
+    for entry in entries(true) {
+        if !keep.contains(&entry.path) && fs::remove_file(&entry.path).is_ok() {
+            count += 1;
+            size  += entry.size;
+        }
+    }
+
+    Ok((count, size))
+}
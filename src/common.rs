@@ -1,4 +1,23 @@
+pub mod error;
 pub mod parser;
 pub mod ipfs;
 pub mod network;
 pub mod daemon;
+pub mod crypto;
+pub mod settings;
+pub mod protocol;
+pub mod store;
+pub mod transport;
+pub mod lock;
+pub mod install;
+pub mod archive;
+pub mod resolve;
+pub mod transaction;
+pub mod history;
+pub mod cache;
+pub mod trust;
+
+#[cfg(test)]
+mod testing;
+
+pub use error::Error;
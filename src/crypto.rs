@@ -0,0 +1,628 @@
+extern crate ring;
+extern crate untrusted;
+
+use ring::signature;
+use ring::signature::KeyPair;
+use sha2::{Sha256, Digest};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parser;
+use crate::ipfs;
+
+// header of the canonical package metadata, prepended to the signed message
+// so that a package signature can never be confused with any other message
+// signed by the same key (e.g., a keyring entry)
+static PKGINFO_HEADER: &str = "pkgman-pkginfo-v1";
+
+// header of the canonical co-signed package metadata, see PKGINFO_HEADER
+static COSIGNATURE_HEADER: &str = "pkgman-cosignature-v1";
+
+// header of the canonical keyring entry, see PKGINFO_HEADER
+static CERTIFICATION_HEADER: &str = "pkgman-certification-v1";
+
+// header of the canonical key rotation statement, see PKGINFO_HEADER
+static ROTATION_HEADER: &str = "pkgman-rotation-v1";
+
+// header of the canonical revocation record, see PKGINFO_HEADER
+static REVOCATION_HEADER: &str = "pkgman-revocation-v1";
+
+// header of the canonical keyring snapshot, see PKGINFO_HEADER
+static KEYRING_HEADER: &str = "pkgman-keyring-v1";
+
+// Key id of a base64-encoded Ed25519 public key
+//
+// The key id is the hex encoding of the first 8 bytes of the SHA256 digest of the
+// raw public key and it is used to find the correct key from the keyring when
+// a package signature is verified
+pub fn key_id(pubkey: &str) -> Option<String> {
+    let raw = match base64::decode(pubkey) {
+        Ok(raw) => raw,
+        Err(_)  => return None
+    };
+
+    let mut sha256 = Sha256::new();
+    sha256.update(&raw);
+
+    Some(sha256.finalize()[..8].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// serialize `fields` one "key=value" pair per line after `header`
+fn canonical(header: &str, fields: &[(&str, String)]) -> Option<String> {
+    let mut out = format!("{}\n", header);
+
+    for (key, value) in fields.iter() {
+        if value.contains('\n') || value.contains('\r') {
+            return None;
+        }
+        out.push_str(&format!("{}={}\n", key, value));
+    }
+
+    Some(out)
+}
+
+// Canonical serialization of the signed fields of parser::PkgInfo
+//
+// The fields are written in a fixed order, one "key=value" pair per line.
+// A field containing a newline would make the serialization ambiguous,
+// so such entries cannot be serialized (and thus neither signed nor verified)
+//
+// The package relations are appended one line per entry, in the order they
+// are listed, and only when they are non-empty so that the signatures of
+// packages without relations stay the same
+pub fn canonical_pkginfo(pkg: &parser::PkgInfo) -> Option<String> {
+    canonical(PKGINFO_HEADER, &pkginfo_fields(pkg))
+}
+
+// signed fields of parser::PkgInfo, see canonical_pkginfo()
+fn pkginfo_fields(pkg: &parser::PkgInfo) -> Vec<(&str, String)> {
+    let mut fields = vec![
+        ("name",      pkg.name.clone()),
+        ("version",   pkg.version.clone()),
+        ("sha256",    pkg.sha256.clone()),
+        ("ipfs",      pkg.ipfs.clone()),
+        ("signer",    pkg.signer.clone()),
+        ("timestamp", pkg.timestamp.to_string()),
+    ];
+
+    // entries signed before the size was added don't have one
+    if pkg.size > 0 {
+        fields.push(("size", pkg.size.to_string()));
+    }
+
+    let relations = [
+        ("depends",   &pkg.depends),
+        ("conflicts", &pkg.conflicts),
+        ("provides",  &pkg.provides),
+        ("replaces",  &pkg.replaces),
+    ];
+
+    for (key, values) in relations.iter() {
+        fields.extend(values.iter().map(|value| (*key, value.clone())));
+    }
+
+    fields
+}
+
+// Fill in the signer, timestamp and signature fields of `pkg`
+//
+// All other fields must be set before calling this function as they are
+// covered by the signature
+pub fn sign_pkginfo(keypair: &signature::Ed25519KeyPair, pkg: &mut parser::PkgInfo) -> Result<(), ipfs::IPFSError> {
+    pkg.signer    = key_id(&base64::encode(keypair.public_key().as_ref())).unwrap();
+    pkg.timestamp = now();
+
+    match canonical_pkginfo(pkg) {
+        Some(msg) => {
+            pkg.signature = base64::encode(keypair.sign(msg.as_bytes()));
+            Ok(())
+        },
+        None => Err(ipfs::IPFSError::SignatureMismatch)
+    }
+}
+
+// Verify the signature of `pkg` against the public keys in `keys`
+//
+// Entries that don't name a signer are legacy entries where only the sha256
+// digest is signed. These are rejected unless `allow_legacy` is set, because
+// such a signature doesn't bind the name or the version of the package and can
+// thus be replayed under any other package entry
+pub fn verify_pkginfo(pkg: &parser::PkgInfo, keys: &[String], allow_legacy: bool) -> Result<(), ipfs::IPFSError> {
+    let sig = match base64::decode(&pkg.signature) {
+        Ok(sig) => sig,
+        Err(_)  => return Err(ipfs::IPFSError::SignatureMismatch)
+    };
+
+    if pkg.signer.is_empty() {
+        if !allow_legacy {
+            println!("Package {} uses a legacy signature, refusing to verify it", pkg.name);
+            return Err(ipfs::IPFSError::SignatureMismatch);
+        }
+
+        // do not exit on failure as there may be multiple keys of which
+        // only one will provide the correct signature
+        for key in keys {
+            if verify(key, pkg.sha256.as_bytes(), &sig) {
+                return Ok(());
+            }
+        }
+
+        return Err(ipfs::IPFSError::SignatureMismatch);
+    }
+
+    let msg = match canonical_pkginfo(pkg) {
+        Some(msg) => msg,
+        None      => return Err(ipfs::IPFSError::SignatureMismatch)
+    };
+
+    for key in keys {
+        if key_id(key).as_deref() == Some(pkg.signer.as_str()) && verify(key, msg.as_bytes(), &sig) {
+            return Ok(());
+        }
+    }
+
+    Err(ipfs::IPFSError::SignatureMismatch)
+}
+
+// certified fields of parser::KeyringEntry
+//
+// The validity period is included only when it's set, so that the
+// certifications of entries without one stay the same
+fn entry_fields(entry: &parser::KeyringEntry) -> Vec<(&str, String)> {
+    let mut fields = vec![
+        ("name",  entry.name.clone()),
+        ("email", entry.email.clone()),
+        ("key",   entry.key.clone()),
+    ];
+
+    if entry.valid_from > 0 {
+        fields.push(("valid_from", entry.valid_from.to_string()));
+    }
+
+    if entry.valid_until > 0 {
+        fields.push(("valid_until", entry.valid_until.to_string()));
+    }
+
+    fields
+}
+
+// Canonical serialization of co-signature `cosig` of `pkg`
+//
+// The co-signature covers the signed package metadata, including the signer
+// and the signing time of the primary signature, and the co-signer and the
+// time of co-signing. Co-signatures aren't part of the canonical package
+// metadata, so they can be added without invalidating other signatures
+pub fn canonical_cosignature(pkg: &parser::PkgInfo, cosig: &parser::Cosignature) -> Option<String> {
+    let mut fields = pkginfo_fields(pkg);
+
+    fields.push(("cosigner", cosig.signer.clone()));
+    fields.push(("cosigned", cosig.timestamp.to_string()));
+
+    canonical(COSIGNATURE_HEADER, &fields)
+}
+
+// Add a co-signature of `pkg` by `keypair`, replacing an earlier co-signature by the same key
+//
+// The primary signer can't co-sign its own package
+pub fn cosign(keypair: &signature::Ed25519KeyPair, pkg: &mut parser::PkgInfo) -> Result<(), ipfs::IPFSError> {
+    let signer = key_id(&base64::encode(keypair.public_key().as_ref())).unwrap();
+
+    if signer == pkg.signer {
+        return Err(ipfs::IPFSError::AlreadyExists);
+    }
+
+    let mut cosig = parser::Cosignature {
+        signer,
+        timestamp: now(),
+        signature: String::new()
+    };
+
+    match canonical_cosignature(pkg, &cosig) {
+        Some(msg) => {
+            cosig.signature = base64::encode(keypair.sign(msg.as_bytes()));
+            pkg.cosignatures.retain(|other| other.signer != cosig.signer);
+            pkg.cosignatures.push(cosig);
+            Ok(())
+        },
+        None => Err(ipfs::IPFSError::SignatureMismatch)
+    }
+}
+
+// Check whether `cosig` is a valid co-signature of `pkg` by base64-encoded public key `key`
+pub fn verify_cosignature(pkg: &parser::PkgInfo, cosig: &parser::Cosignature, key: &str) -> bool {
+    let sig = base64::decode(&cosig.signature).unwrap_or_default();
+
+    match canonical_cosignature(pkg, cosig) {
+        Some(msg) => key_id(key).as_deref() == Some(cosig.signer.as_str()) && verify(key, msg.as_bytes(), &sig),
+        None      => false
+    }
+}
+
+// Canonical serialization of the certified fields of parser::KeyringEntry
+//
+// A certification binds the name and the email of the maintainer and the
+// validity period to the key, so that a certified key can't be presented
+// under another name or used outside of its validity period
+pub fn canonical_entry(entry: &parser::KeyringEntry) -> Option<String> {
+    canonical(CERTIFICATION_HEADER, &entry_fields(entry))
+}
+
+// Add a certification of `entry` by `keypair`, replacing an earlier certification by the same key
+pub fn certify(keypair: &signature::Ed25519KeyPair, entry: &mut parser::KeyringEntry) -> Result<(), ipfs::IPFSError> {
+    let signer = key_id(&base64::encode(keypair.public_key().as_ref())).unwrap();
+
+    let msg = match canonical_entry(entry) {
+        Some(msg) => msg,
+        None      => return Err(ipfs::IPFSError::SignatureMismatch)
+    };
+
+    entry.certifications.retain(|cert| cert.signer != signer);
+    entry.certifications.push(parser::Certification {
+        signer,
+        signature: base64::encode(keypair.sign(msg.as_bytes()))
+    });
+
+    Ok(())
+}
+
+// Check whether `cert` is a valid certification of `entry` by base64-encoded public key `key`
+pub fn verify_certification(entry: &parser::KeyringEntry, cert: &parser::Certification, key: &str) -> bool {
+    let sig = base64::decode(&cert.signature).unwrap_or_default();
+
+    match canonical_entry(entry) {
+        Some(msg) => key_id(key).as_deref() == Some(cert.signer.as_str()) && verify(key, msg.as_bytes(), &sig),
+        None      => false
+    }
+}
+
+// Canonical serialization of the rotation statement of `entry` from
+// the key of `rotation`, which covers the certified fields of the entry
+pub fn canonical_rotation(entry: &parser::KeyringEntry, rotation: &parser::Rotation) -> Option<String> {
+    let mut fields = entry_fields(entry);
+
+    fields.push(("from",      rotation.key.clone()));
+    fields.push(("timestamp", rotation.timestamp.to_string()));
+
+    canonical(ROTATION_HEADER, &fields)
+}
+
+// Sign the rotation from the key of `keypair` to the key of `entry` at `timestamp`
+//
+// All other fields of `entry` must be set before calling this function
+pub fn rotate(
+    keypair: &signature::Ed25519KeyPair,
+    entry: &mut parser::KeyringEntry,
+    timestamp: u64
+) -> Result<(), ipfs::IPFSError> {
+
+    let mut rotation = parser::Rotation {
+        key:       base64::encode(keypair.public_key().as_ref()),
+        timestamp,
+        signature: String::new()
+    };
+
+    match canonical_rotation(entry, &rotation) {
+        Some(msg) => {
+            rotation.signature = base64::encode(keypair.sign(msg.as_bytes()));
+            entry.rotation     = Some(rotation);
+            Ok(())
+        },
+        None => Err(ipfs::IPFSError::SignatureMismatch)
+    }
+}
+
+// Check whether the rotation statement of `entry` is signed by the previous key
+pub fn verify_rotation(entry: &parser::KeyringEntry) -> bool {
+    let rotation = match &entry.rotation {
+        Some(rotation) => rotation,
+        None           => return false
+    };
+
+    let sig = base64::decode(&rotation.signature).unwrap_or_default();
+
+    match canonical_rotation(entry, rotation) {
+        Some(msg) => rotation.key != entry.key && verify(&rotation.key, msg.as_bytes(), &sig),
+        None      => false
+    }
+}
+
+// Canonical serialization of the signed fields of parser::Revocation
+pub fn canonical_revocation(rev: &parser::Revocation) -> Option<String> {
+    canonical(REVOCATION_HEADER, &[
+        ("key",       rev.key.clone()),
+        ("timestamp", rev.timestamp.to_string()),
+        ("reason",    rev.reason.clone()),
+        ("signer",    rev.signer.clone()),
+    ])
+}
+
+// Create a record that revokes `key` at `timestamp`, signed by `keypair`
+pub fn revoke(
+    keypair: &signature::Ed25519KeyPair,
+    key: &str,
+    timestamp: u64,
+    reason: &str
+) -> Result<parser::Revocation, ipfs::IPFSError> {
+
+    let mut rev = parser::Revocation {
+        key:       key.to_string(),
+        timestamp,
+        reason:    reason.to_string(),
+        signer:    key_id(&base64::encode(keypair.public_key().as_ref())).unwrap(),
+        signature: String::new()
+    };
+
+    match canonical_revocation(&rev) {
+        Some(msg) => {
+            rev.signature = base64::encode(keypair.sign(msg.as_bytes()));
+            Ok(rev)
+        },
+        None => Err(ipfs::IPFSError::SignatureMismatch)
+    }
+}
+
+// Check whether `rev` is signed by base64-encoded public key `key`
+pub fn verify_revocation(rev: &parser::Revocation, key: &str) -> bool {
+    let sig = base64::decode(&rev.signature).unwrap_or_default();
+
+    match canonical_revocation(rev) {
+        Some(msg) => key_id(key).as_deref() == Some(rev.signer.as_str()) && verify(key, msg.as_bytes(), &sig),
+        None      => false
+    }
+}
+
+// Canonical serialization of keyring snapshot `keyring`
+//
+// Every field of every entry, revocation and package owner is included, in the
+// order they are listed, so that no entry can be added, dropped or modified
+// without the trust anchor that signed the snapshot. Each entry starts with its
+// name, each revocation with the revoked key and each owner with its key, and
+// the fields are always written in the same order, so the serialization of two
+// different snapshots can't be the same
+pub fn canonical_keyring(keyring: &parser::KeyringConfig) -> Option<String> {
+    let mut fields = vec![
+        ("serial",  keyring.serial.to_string()),
+        ("expires", keyring.expires.to_string()),
+        ("signer",  keyring.signer.clone()),
+    ];
+
+    for entry in keyring.signers.iter() {
+        fields.extend(vec![
+            ("name",        entry.name.clone()),
+            ("email",       entry.email.clone()),
+            ("key",         entry.key.clone()),
+            ("signature",   entry.signature.clone()),
+            ("valid_from",  entry.valid_from.to_string()),
+            ("valid_until", entry.valid_until.to_string()),
+        ]);
+
+        for cert in entry.certifications.iter() {
+            fields.push(("certification_signer",    cert.signer.clone()));
+            fields.push(("certification_signature", cert.signature.clone()));
+        }
+
+        if let Some(rotation) = &entry.rotation {
+            fields.push(("rotation_key",       rotation.key.clone()));
+            fields.push(("rotation_timestamp", rotation.timestamp.to_string()));
+            fields.push(("rotation_signature", rotation.signature.clone()));
+        }
+    }
+
+    for rev in keyring.revocations.iter() {
+        fields.extend(vec![
+            ("revoked_key",          rev.key.clone()),
+            ("revocation_timestamp", rev.timestamp.to_string()),
+            ("revocation_reason",    rev.reason.clone()),
+            ("revocation_signer",    rev.signer.clone()),
+            ("revocation_signature", rev.signature.clone()),
+        ]);
+    }
+
+    for owner in keyring.owners.iter() {
+        fields.push(("owner", owner.key.clone()));
+        fields.extend(owner.packages.iter().map(|pattern| ("owner_package", pattern.clone())));
+    }
+
+    canonical(KEYRING_HEADER, &fields)
+}
+
+// Fill in the signer and signature fields of keyring snapshot `keyring`
+//
+// The serial and the expiry must be set before calling this function
+pub fn sign_keyring(keypair: &signature::Ed25519KeyPair, keyring: &mut parser::KeyringConfig) -> Result<(), ipfs::IPFSError> {
+    keyring.signer = key_id(&base64::encode(keypair.public_key().as_ref())).unwrap();
+
+    match canonical_keyring(keyring) {
+        Some(msg) => {
+            keyring.signature = base64::encode(keypair.sign(msg.as_bytes()));
+            Ok(())
+        },
+        None => Err(ipfs::IPFSError::SignatureMismatch)
+    }
+}
+
+// Check whether keyring snapshot `keyring` is signed by base64-encoded public key `key`
+pub fn verify_keyring(keyring: &parser::KeyringConfig, key: &str) -> bool {
+    let sig = base64::decode(&keyring.signature).unwrap_or_default();
+
+    match canonical_keyring(keyring) {
+        Some(msg) => key_id(key).as_deref() == Some(keyring.signer.as_str()) && verify(key, msg.as_bytes(), &sig),
+        None      => false
+    }
+}
+
+// verify `sig` of `msg` using base64-encoded public key `key`
+pub fn verify(key: &str, msg: &[u8], sig: &[u8]) -> bool {
+    let raw = match base64::decode(key) {
+        Ok(raw) => raw,
+        Err(_)  => return false
+    };
+
+    signature::UnparsedPublicKey::new(&signature::ED25519, raw)
+        .verify(msg, sig)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn signed_pkginfo_verifies() {
+        let (keypair, key) = testing::keypair();
+        let pkg = testing::signed(&keypair, "hello", "1.0");
+
+        assert!(verify_pkginfo(&pkg, &[key], false).is_ok());
+    }
+
+    #[test]
+    fn pkginfo_of_other_key_is_rejected() {
+        let (keypair, _) = testing::keypair();
+        let (_, other)   = testing::keypair();
+        let pkg = testing::signed(&keypair, "hello", "1.0");
+
+        assert!(verify_pkginfo(&pkg, &[other], false).is_err());
+    }
+
+    #[test]
+    fn modified_pkginfo_is_rejected() {
+        let (keypair, key) = testing::keypair();
+        let mut pkg = testing::signed(&keypair, "hello", "1.0");
+
+        pkg.version = "1.1".to_string();
+        assert!(verify_pkginfo(&pkg, std::slice::from_ref(&key), false).is_err());
+
+        pkg.version = "1.0".to_string();
+        pkg.depends.push("evil".to_string());
+        assert!(verify_pkginfo(&pkg, &[key], false).is_err());
+    }
+
+    #[test]
+    fn bad_signature_is_rejected() {
+        let (keypair, key) = testing::keypair();
+        let mut pkg = testing::signed(&keypair, "hello", "1.0");
+        let mut sig = base64::decode(&pkg.signature).unwrap();
+
+        sig[0] ^= 1;
+        pkg.signature = base64::encode(&sig);
+        assert!(verify_pkginfo(&pkg, std::slice::from_ref(&key), false).is_err());
+
+        pkg.signature = "not base64".to_string();
+        assert!(verify_pkginfo(&pkg, &[key], false).is_err());
+    }
+
+    #[test]
+    fn legacy_signature_needs_allow_legacy() {
+        let (keypair, key) = testing::keypair();
+        let mut pkg = testing::pkginfo("hello", "1.0");
+
+        pkg.sha256    = "0".repeat(64);
+        pkg.signature = base64::encode(keypair.sign(pkg.sha256.as_bytes()));
+
+        assert!(verify_pkginfo(&pkg, std::slice::from_ref(&key), false).is_err());
+        assert!(verify_pkginfo(&pkg, &[key], true).is_ok());
+    }
+
+    #[test]
+    fn certification_covers_the_entry() {
+        let (anchor, anchor_key) = testing::keypair();
+        let (_, key) = testing::keypair();
+        let mut entry = testing::entry("alice", &key, &[&anchor]);
+
+        assert!(verify_certification(&entry, &entry.certifications[0], &anchor_key));
+
+        entry.name = "mallory".to_string();
+        assert!(!verify_certification(&entry, &entry.certifications[0], &anchor_key));
+    }
+
+    #[test]
+    fn revocation_is_bound_to_its_signer() {
+        let (keypair, key) = testing::keypair();
+        let (_, other)     = testing::keypair();
+        let mut rev = revoke(&keypair, &key, 100, "lost").unwrap();
+
+        assert!(verify_revocation(&rev, &key));
+        assert!(!verify_revocation(&rev, &other));
+
+        rev.timestamp = 200;
+        assert!(!verify_revocation(&rev, &key));
+    }
+
+    #[test]
+    fn rotation_is_signed_by_previous_key() {
+        let (old, _)  = testing::keypair();
+        let (_, key)  = testing::keypair();
+        let mut entry = testing::entry("alice", &key, &[]);
+
+        rotate(&old, &mut entry, 100).unwrap();
+        assert!(verify_rotation(&entry));
+
+        entry.rotation.as_mut().unwrap().timestamp = 50;
+        assert!(!verify_rotation(&entry));
+    }
+
+    #[test]
+    fn keyring_snapshot_covers_every_entry() {
+        let (anchor, anchor_key) = testing::keypair();
+        let (_, key) = testing::keypair();
+        let mut keyring = parser::KeyringConfig {
+            serial:  1,
+            expires: now() + 60,
+            signers: vec![testing::entry("alice", &key, &[&anchor])],
+            ..parser::KeyringConfig::default()
+        };
+
+        sign_keyring(&anchor, &mut keyring).unwrap();
+        assert!(verify_keyring(&keyring, &anchor_key));
+
+        let mut dropped = keyring.clone();
+        dropped.signers.clear();
+        assert!(!verify_keyring(&dropped, &anchor_key));
+
+        let mut replayed = keyring;
+        replayed.serial = 2;
+        assert!(!verify_keyring(&replayed, &anchor_key));
+    }
+
+    #[test]
+    fn keyring_snapshot_covers_the_owners() {
+        let (anchor, anchor_key) = testing::keypair();
+        let (_, key) = testing::keypair();
+        let mut keyring = parser::KeyringConfig {
+            serial:  1,
+            expires: now() + 60,
+            owners:  vec![parser::Ownership { key: key.clone(), packages: vec!["hello*".to_string()] }],
+            ..parser::KeyringConfig::default()
+        };
+
+        sign_keyring(&anchor, &mut keyring).unwrap();
+        assert!(verify_keyring(&keyring, &anchor_key));
+
+        keyring.owners[0].packages.push("*".to_string());
+        assert!(!verify_keyring(&keyring, &anchor_key));
+
+        keyring.owners[0].packages.pop();
+        keyring.owners.push(parser::Ownership { key, packages: vec!["glibc".to_string()] });
+        assert!(!verify_keyring(&keyring, &anchor_key));
+    }
+
+    #[test]
+    fn cosignature_covers_the_entry() {
+        let (keypair, _)    = testing::keypair();
+        let (cosigner, key) = testing::keypair();
+        let mut pkg = testing::signed(&keypair, "hello", "1.0");
+
+        cosign(&cosigner, &mut pkg).unwrap();
+        assert!(verify_cosignature(&pkg, &pkg.cosignatures[0], &key));
+
+        pkg.size = 1;
+        assert!(!verify_cosignature(&pkg, &pkg.cosignatures[0], &key));
+    }
+}
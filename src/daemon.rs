@@ -1,110 +1,128 @@
-extern crate base64;
-
-use futures::{select, future, FutureExt, TryStreamExt};
-use std::sync::mpsc;
-use std::thread;
-use std::fs::File;
-use std::io::prelude::*;
+use futures::{select, StreamExt};
+use std::collections::HashMap;
 
+use crate::Error;
 use crate::parser;
 use crate::ipfs;
+use crate::protocol;
+use crate::resolve;
+use crate::store;
+use crate::transport;
 
-#[actix_rt::main]
-async fn handle_query(rx: mpsc::Receiver<(&'static str, String)>) {
+// Package `name`, or if there's no such package, a package that provides it
+fn lookup<'a>(map: &'a HashMap<String, parser::PkgInfo>, name: &str) -> Option<&'a parser::PkgInfo> {
+    if let Some(info) = map.get(name) {
+        return Some(info);
+    }
 
-    let map = parser::get_pkgs(&parser::expand("PKGLIST_bootstrap.toml")).unwrap();
-    let client = ipfs::get_client();
+    let mut providers: Vec<&parser::PkgInfo> = map
+        .values()
+        .filter(|pkg| resolve::provides(pkg, name))
+        .collect();
 
-    loop {
-        let (topic, msg) = rx.recv().unwrap();
+    providers.sort_by(|a, b| a.name.cmp(&b.name));
+    providers.into_iter().next()
+}
+
+async fn handle_query(
+    transport: &dyn transport::Transport,
+    map: &HashMap<String, parser::PkgInfo>,
+    topic: &str,
+    req: protocol::Envelope
+) -> Result<(), Error> {
+
+    if topic == ipfs::PST_BATCH_QUERY {
+        let query: protocol::BatchQuery = match toml::from_str(&req.payload) {
+            Ok(query) => query,
+            Err(_)    => return Ok(())
+        };
 
-        if topic == ipfs::PST_PACKAGE_QUERY {
-            match map.get(&msg) {
+        let mut answer = protocol::BatchAnswer::default();
+
+        for name in query.names.iter() {
+            match lookup(map, name) {
                 Some(info) => {
-                    println!("package {} found!", msg);
-                    client.pubsub_pub(
-                        ipfs::PST_PACKAGE,
-                        &toml::to_string(&info).unwrap()
-                    ).await.unwrap();
+                    if !answer.packages.iter().any(|pkg| pkg.name == info.name) {
+                        answer.packages.push(info.clone());
+                    }
                 },
-                None => {
-                    println!("No package {} found", msg);
-                }
+                None => println!("No package {} found", name)
             }
-        } else if topic == ipfs::PST_KEYRING_QUERY {
-            let mut contents = String::new();
-            File::open(parser::expand("KEYRING_bootstrap.toml"))
-                .unwrap()
-                .read_to_string(&mut contents)
-                .unwrap();
-
-            client.pubsub_pub(
-                ipfs::PST_KEYRING,
-                &contents
-            ).await.unwrap();
         }
+
+        println!("{} of {} packages found", answer.packages.len(), query.names.len());
+
+        transport.publish(
+            ipfs::PST_BATCH,
+            &protocol::encode(&protocol::response(&req, &toml::to_string(&answer).unwrap_or_default()))
+        ).await?;
+    } else if topic == ipfs::PST_KEYRING_QUERY {
+        let contents = parser::get_file_contents(&parser::expand("KEYRING_bootstrap.toml"))?;
+        let contents = String::from_utf8_lossy(&contents);
+
+        transport.publish(
+            ipfs::PST_KEYRING,
+            &protocol::encode(&protocol::response(&req, &contents))
+        ).await?;
     }
+
+    Ok(())
 }
 
-// create a channel which is used to communicate between this control flow
-// and the thread that is responsible for answering to PUBSUB_TOPIC_QUERY
-// requests. Each time a new message is received from that pubsub interface,
-// the message is passed to the handle_query() fuction which holds the list
-// of all available packages that the network contains.
+// Subscribe to the batch and keyring query topics and answer each query
+// received from them using handle_query() which holds the list of all
+// available packages that the network contains.
 //
-// The function the responds to the query by sending serialized parser::PkgInfo
-// using the PUBSUB_TOPIC_QURY_RESP interface. This way there can be multiple
-// nodes in the network serving package query requests.
+// A batch query on PST_BATCH_QUERY names every package a client needs, and the
+// daemon answers with a single protocol::BatchAnswer on PST_BATCH containing
+// each package it knows, or a package providing the requested name. This way
+// there can be multiple nodes in the network serving package queries and the
+// client merges their answers. Queries and answers are wrapped in a
+// protocol::Envelope and each answer echoes the request id of the query so the
+// requester can match the answers to its own queries.
 //
 // The keyring pubsub interfaces are used to query and distribute maintainer
 // information. This maintainer info contains the name, email and public key
 // of all accepted mainters as well as a signature for each maintainer's
 // public key which is signed by the first node of the system to prevent
 // malicious third parties from distributing their own public keys
-pub async fn daemon() {
-
-    let (tx, rx) = mpsc::channel();
-
-    thread::spawn(move|| { handle_query(rx) });
-
-    let mut sub_query = {
-        ipfs::get_client()
-            .pubsub_sub(ipfs::PST_PACKAGE_QUERY, false)
-            .try_for_each(|msg| {
-                tx.send((
-                    ipfs::PST_PACKAGE_QUERY,
-                    std::str::from_utf8(
-                        &base64::decode(msg.data.unwrap()).unwrap()
-                    ).unwrap().to_owned())
-                ).unwrap();
-                future::ok(())
-            })
-            .fuse()
-    };
-
-    let mut sub_keyring = {
-        ipfs::get_client()
-            .pubsub_sub(ipfs::PST_KEYRING_QUERY, false)
-            .try_for_each(|msg| {
-                tx.send((
-                    ipfs::PST_KEYRING_QUERY,
-                    std::str::from_utf8(
-                        &base64::decode(msg.data.unwrap()).unwrap()
-                    ).unwrap().to_owned())
-                ).unwrap();
-                future::ok(())
-            })
-            .fuse()
-    };
-
-    select! {
-        res = sub_keyring => match res {
-            Ok(_)    => {} ,
-            Err(err) => println!("Failed to read keyring query message: {}", err)
-        },
-        res = sub_query => match res {
-            Ok(_)    => { },
-            Err(err) => println!("Failed to ready package query message: {}", err)
-        },
+//
+// Before answering any queries, all packages of the daemon's package list are
+// pinned to `store` so that the daemon also serves the contents of the packages
+// it advertises
+pub async fn daemon(store: &dyn store::ContentStore, transport: &dyn transport::Transport) -> Result<(), Error> {
+
+    let map = parser::get_pkgs(&parser::expand("PKGLIST_bootstrap.toml"))?;
+
+    for (name, pkg) in map.iter() {
+        if let Err(err) = store.pin(&pkg.ipfs).await {
+            println!("Failed to pin package {}: {}", name, err);
+        }
     }
+
+    let mut sub_batch   = transport.subscribe(ipfs::PST_BATCH_QUERY).await?.fuse();
+    let mut sub_keyring = transport.subscribe(ipfs::PST_KEYRING_QUERY).await?.fuse();
+
+    loop {
+        let (topic, msg) = select! {
+            msg = sub_batch.next() => match msg {
+                Some(msg) => (ipfs::PST_BATCH_QUERY, msg),
+                None      => break
+            },
+            msg = sub_keyring.next() => match msg {
+                Some(msg) => (ipfs::PST_KEYRING_QUERY, msg),
+                None      => break
+            },
+        };
+
+        // ignore messages that are not valid protocol envelopes
+        if let Some(req) = protocol::decode(&msg.data) {
+            if let Err(err) = handle_query(transport, &map, topic, req).await {
+                println!("Failed to answer query from {}: {}", msg.from, err);
+            }
+        }
+    }
+
+    println!("Subscription closed, stopping the daemon");
+    Ok(())
 }
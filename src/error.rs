@@ -0,0 +1,54 @@
+use std::fmt;
+
+use crate::parser::ParserError;
+use crate::ipfs::IPFSError;
+use crate::install::InstallError;
+use crate::archive::ArchiveError;
+use crate::resolve::ResolveError;
+use crate::history::HistoryError;
+use crate::trust::TrustError;
+
+// Error returned by the public functions of the library
+#[derive(Debug)]
+pub enum Error {
+    Parser(ParserError),
+    IPFS(IPFSError),
+    Install(InstallError),
+    Archive(ArchiveError),
+    Resolve(ResolveError),
+    History(HistoryError),
+    Trust(TrustError),
+
+    // package database is locked by another process (pid, if known)
+    Locked(Option<u32>)
+}
+
+impl From<ParserError> for Error {
+    fn from(err: ParserError) -> Self {
+        Error::Parser(err)
+    }
+}
+
+impl From<IPFSError> for Error {
+    fn from(err: IPFSError) -> Self {
+        Error::IPFS(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parser(err) => write!(f, "{}", err),
+            Error::IPFS(err)   => write!(f, "{}", err),
+            Error::Install(err) => write!(f, "{}", err),
+            Error::Archive(err) => write!(f, "{}", err),
+            Error::Resolve(err) => write!(f, "{}", err),
+            Error::History(err) => write!(f, "{}", err),
+            Error::Trust(err)   => write!(f, "{}", err),
+            Error::Locked(Some(pid)) => write!(f, "another pkgman is running (pid {})", pid),
+            Error::Locked(None)      => write!(f, "another pkgman is running")
+        }
+    }
+}
+
+impl std::error::Error for Error { }
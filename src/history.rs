@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Error;
+use crate::crypto;
+use crate::install;
+use crate::network;
+use crate::parser;
+use crate::settings;
+use crate::store;
+
+#[derive(Debug)]
+pub enum HistoryError {
+    NoGenerations,
+    NoPrevious,
+    NotFound(u64),
+
+    // the requested generation is the current one
+    AlreadyCurrent(u64),
+
+    // the installed packages are the same as the ones of the requested generation
+    Unchanged(u64)
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::NoGenerations       => write!(f, "no generations recorded"),
+            HistoryError::NoPrevious          => write!(f, "no previous generation"),
+            HistoryError::NotFound(num)       => write!(f, "generation {} not found", num),
+            HistoryError::AlreadyCurrent(num) => write!(f, "generation {} is the current generation", num),
+            HistoryError::Unchanged(num)      => write!(f, "installed packages already match generation {}", num)
+        }
+    }
+}
+
+impl From<HistoryError> for Error {
+    fn from(err: HistoryError) -> Self {
+        Error::History(err)
+    }
+}
+
+// Record the current set of installed packages as a new generation
+//
+// Called after each committed transaction, returns the number of the new generation
+pub fn record() -> Result<u64, Error> {
+    let pkgs   = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let number = parser::get_generations()?.last().map(|last| last.number + 1).unwrap_or(1);
+
+    let mut dependencies: Vec<String> = parser::get_manifests()?
+        .into_iter()
+        .filter(|manifest| manifest.dependency && pkgs.contains_key(&manifest.name))
+        .map(|manifest| manifest.name)
+        .collect();
+
+    let mut packages: Vec<parser::PkgInfo> = pkgs.into_values().collect();
+
+    dependencies.sort();
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    parser::save_generation(&parser::Generation {
+        number,
+        timestamp: crypto::now(),
+        dependencies,
+        packages
+    })?;
+
+    Ok(number)
+}
+
+// Format seconds since UNIX epoch as a UTC date and time
+pub fn format_time(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // civil date from the number of days since 1970-01-01
+    let z     = days + 719_468;
+    let era   = z.div_euclid(146_097);
+    let doe   = z.rem_euclid(146_097);
+    let yoe   = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy   = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp    = (5 * doy + 2) / 153;
+    let day   = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year  = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// Return to generation `number`, or to the generation before the current one if `number` is None
+//
+// The packages of the generation are fetched by their recorded content identifiers
+// and verified against the current keyring just like downloaded packages, and the
+// difference to the installed packages is applied as a single transaction, which
+// also restores which packages were installed as dependencies. The result is
+// recorded as a new generation, nothing is done if there's no difference. Files
+// in the prefix that no package installed are replaced only if `overwrite` is
+// set. Returns the number of the generation that was restored
+pub async fn rollback(store: &dyn store::ContentStore, number: Option<u64>, overwrite: bool) -> Result<u64, Error> {
+    let generations = parser::get_generations()?;
+
+    let current = match generations.last() {
+        Some(current) => current.number,
+        None          => return Err(Error::History(HistoryError::NoGenerations))
+    };
+
+    let number = match number {
+        Some(number) => number,
+        None         => match generations.iter().rev().nth(1) {
+            Some(previous) => previous.number,
+            None           => return Err(Error::History(HistoryError::NoPrevious))
+        }
+    };
+
+    if number == current {
+        return Err(Error::History(HistoryError::AlreadyCurrent(number)));
+    }
+
+    let target = match generations.into_iter().find(|generation| generation.number == number) {
+        Some(target) => target,
+        None         => return Err(Error::History(HistoryError::NotFound(number)))
+    };
+
+    let installed = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let wanted: HashMap<String, parser::PkgInfo> = target.packages
+        .into_iter()
+        .map(|pkg| (pkg.name.clone(), pkg))
+        .collect();
+
+    let mut names: Vec<&String> = wanted.keys().collect();
+
+    names.sort();
+
+    let todo: Vec<parser::PkgInfo> = names
+        .iter()
+        .map(|name| &wanted[*name])
+        .filter(|pkg| match installed.get(&pkg.name) {
+            Some(cur) => cur.version != pkg.version || cur.sha256 != pkg.sha256 || cur.ipfs != pkg.ipfs,
+            None      => true
+        })
+        .cloned()
+        .collect();
+
+    let mut removed: Vec<&String> = installed.keys().filter(|name| !wanted.contains_key(*name)).collect();
+
+    removed.sort();
+
+    let installed_as_dependency: HashMap<String, bool> = parser::get_manifests()?
+        .into_iter()
+        .map(|manifest| (manifest.name, manifest.dependency))
+        .collect();
+
+    // every package gets the flag of the generation, including the reinstalled
+    // ones as an upgrade would keep an explicitly installed package explicit
+    let dependencies = &target.dependencies;
+    let marks: Vec<(String, bool)> = names
+        .into_iter()
+        .map(|name| (name.clone(), !dependencies.contains(name)))
+        .filter(|(name, explicit)| {
+            todo.iter().any(|pkg| pkg.name == *name) || installed_as_dependency.get(name) == Some(explicit)
+        })
+        .collect();
+
+    if todo.is_empty() && removed.is_empty() && marks.is_empty() {
+        return Err(Error::History(HistoryError::Unchanged(number)));
+    }
+
+    let fetched = network::fetch(store, &todo).await?;
+    let mut steps: Vec<install::Step> = Vec::new();
+
+    for (pkg, package) in todo.into_iter().zip(fetched) {
+        let package  = package?;
+        let explicit = !target.dependencies.contains(&pkg.name);
+
+        println!("Installing {} {}", pkg.name, pkg.version);
+        steps.push(install::Step::Install { pkg: Box::new(pkg), package, explicit });
+    }
+
+    for name in removed {
+        println!("Removing {}", name);
+        steps.push(install::Step::Remove(name.clone()));
+    }
+
+    for (name, explicit) in marks {
+        steps.push(install::Step::Mark { name, explicit });
+    }
+
+    install::apply(steps, &install::prefix(&settings::get_settings()?), overwrite)?;
+    Ok(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn install(home: &testing::Home, name: &str, explicit: bool) {
+        let package = testing::package(home, name, "1.0", &[(&format!("lib/{}.so", name), name)]);
+
+        install::apply(vec![install::Step::Install {
+            pkg:     Box::new(testing::pkginfo(name, "1.0")),
+            package,
+            explicit
+        }], home.join("prefix").to_str().unwrap(), false).unwrap();
+    }
+
+    fn dependency(name: &str) -> bool {
+        parser::get_manifest(name).unwrap().dependency
+    }
+
+    #[actix_rt::test]
+    async fn rollback_restores_explicit_flags() {
+        let home  = testing::home();
+        let store = store::DirStore::new(home.join("store").to_str().unwrap());
+
+        install(&home, "libfoo", false);
+        install(&home, "app", true);
+
+        // libfoo is requested explicitly later on
+        install::apply(vec![install::Step::Mark {
+            name:     "libfoo".to_string(),
+            explicit: true
+        }], home.join("prefix").to_str().unwrap(), false).unwrap();
+
+        assert!(!dependency("libfoo"));
+
+        assert_eq!(rollback(&store, Some(2), false).await.unwrap(), 2);
+        assert!(dependency("libfoo"));
+        assert!(!dependency("app"));
+        assert_eq!(parser::get_generations().unwrap().len(), 4);
+
+        // nothing to do, and no new generation
+        assert!(matches!(
+            rollback(&store, Some(2), false).await,
+            Err(Error::History(HistoryError::Unchanged(2)))
+        ));
+        assert_eq!(parser::get_generations().unwrap().len(), 4);
+    }
+}
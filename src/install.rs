@@ -0,0 +1,444 @@
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+use crate::archive;
+use crate::history;
+use crate::parser;
+use crate::resolve;
+use crate::settings;
+use crate::transaction::Transaction;
+
+#[derive(Debug)]
+pub enum InstallError {
+    WriteError(String),
+    NotInstalled(String),
+
+    // package is needed by other installed packages
+    RequiredBy {
+        name:       String,
+        dependents: Vec<String>
+    },
+
+    // file is already installed by another package, or exists without
+    // being installed by any package if `owner` is None
+    FileConflict {
+        path:  String,
+        owner: Option<String>
+    }
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstallError::WriteError(path)   => write!(f, "{}: failed to write file", path),
+            InstallError::NotInstalled(name) => write!(f, "{} is not installed", name),
+            InstallError::RequiredBy { name, dependents } => {
+                write!(f, "{} is required by {}", name, dependents.join(", "))
+            },
+            InstallError::FileConflict { path, owner: Some(owner) } => {
+                write!(f, "{}: file is owned by package {}", path, owner)
+            },
+            InstallError::FileConflict { path, owner: None } => {
+                write!(f, "{}: file exists and is not owned by any package, use --overwrite to replace it", path)
+            }
+        }
+    }
+}
+
+impl From<InstallError> for Error {
+    fn from(err: InstallError) -> Self {
+        Error::Install(err)
+    }
+}
+
+pub fn prefix(settings: &settings::Settings) -> String {
+    settings::expand_home(&settings.prefix)
+}
+
+fn sha256_file(path: &Path) -> Option<String> {
+    let mut sha256 = Sha256::new();
+    sha256.update(&fs::read(path).ok()?);
+
+    Some(format!("{:x}", sha256.finalize()))
+}
+
+// Check whether `file` still has the contents that were installed by pkgman
+//
+// Files modified after the installation are reported, they are left in place
+// when the package is removed
+fn unmodified(prefix: &Path, file: &parser::ManifestFile) -> bool {
+    let path = prefix.join(&file.path);
+
+    let installed = if file.link.is_empty() {
+        sha256_file(&path).map(|digest| digest == file.sha256)
+    } else {
+        fs::read_link(&path).ok().map(|target| target == Path::new(&file.link))
+    };
+
+    if installed == Some(false) {
+        println!("{} has been modified, not removing it", path.display());
+    }
+
+    installed == Some(true)
+}
+
+// Change to the installed packages, see apply()
+pub enum Step {
+    // install `package`, verified against the signed metadata `pkg`
+    Install {
+        pkg:      Box<parser::PkgInfo>,
+        package:  archive::Package,
+        explicit: bool
+    },
+
+    Remove(String),
+
+    // record whether installed package `name` was requested by the user,
+    // overriding the flag set by Step::Install
+    Mark {
+        name:     String,
+        explicit: bool
+    }
+}
+
+// Apply `steps` to the installed packages as a single transaction
+//
+// Packages are installed to `prefix`. If an older version of a package is
+// installed, the files that are not part of the new version are removed.
+// Nothing is changed if any file conflicts with a file of another package,
+// or with a file that no package installed unless `overwrite` is set, and if
+// anything fails while the changes are applied, all files, manifests and
+// PKGLIST.toml are restored to their previous state
+//
+// `explicit` of Step::Install tells whether the package was requested by the
+// user, a package that was once requested explicitly stays explicit on upgrades
+pub fn apply(steps: Vec<Step>, prefix: &str, overwrite: bool) -> Result<(), Error> {
+    let prefix   = PathBuf::from(prefix);
+    let mut pkgs = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let mut old: HashMap<String, parser::Manifest> = parser::get_manifests()?
+        .into_iter()
+        .map(|manifest| (manifest.name.clone(), manifest))
+        .collect();
+
+    let changed: Vec<&str> = steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Install { pkg, .. } => Some(pkg.name.as_str()),
+            Step::Remove(name)        => Some(name.as_str()),
+            Step::Mark { .. }         => None
+        })
+        .collect();
+
+    // files installed by any package, including the ones changed by the transaction
+    let mut installed: HashSet<PathBuf> = HashSet::new();
+
+    for manifest in old.values() {
+        for file in manifest.files.iter() {
+            installed.insert(Path::new(&manifest.prefix).join(&file.path));
+        }
+    }
+
+    // owners of the files that are not touched by the transaction
+    let mut owners: HashMap<PathBuf, String> = HashMap::new();
+
+    for manifest in old.values().filter(|manifest| !changed.contains(&manifest.name.as_str())) {
+        for file in manifest.files.iter() {
+            owners.insert(Path::new(&manifest.prefix).join(&file.path), manifest.name.clone());
+        }
+    }
+
+    let mut txn = Transaction::new();
+    let mut manifests: Vec<parser::Manifest> = Vec::new();
+    let mut dropped: Vec<String> = Vec::new();
+    let mut removed: Vec<(PathBuf, parser::ManifestFile)> = Vec::new();
+    let mut marks: Vec<(String, bool)> = Vec::new();
+
+    for step in steps {
+        let previous = match step {
+            Step::Install { pkg, package, explicit } => {
+                let paths = package.files
+                    .iter()
+                    .map(|file| file.path.as_path())
+                    .chain(package.links.iter().map(|link| Path::new(&link.path)));
+
+                for path in paths {
+                    let dest = prefix.join(path);
+
+                    if let Some(owner) = owners.get(&dest) {
+                        return Err(Error::Install(InstallError::FileConflict {
+                            path:  dest.display().to_string(),
+                            owner: Some(owner.clone())
+                        }));
+                    }
+
+                    // e.g., a file created by the user, its backup is removed
+                    // once the transaction is committed
+                    if !overwrite && !installed.contains(&dest) && fs::symlink_metadata(&dest).is_ok() {
+                        return Err(Error::Install(InstallError::FileConflict {
+                            path:  dest.display().to_string(),
+                            owner: None
+                        }));
+                    }
+
+                    owners.insert(dest, pkg.name.clone());
+                }
+
+                archive::unpack(&package, |file, data| {
+                    let dest = prefix.join(&file.path);
+
+                    txn.stage_file(&dest, data, file.mode)
+                        .map_err(|_| archive::ArchiveError::WriteError(dest.display().to_string()))
+                })?;
+
+                let mut files: Vec<parser::ManifestFile> = Vec::new();
+
+                for file in package.files.iter() {
+                    files.push(parser::ManifestFile {
+                        path:   file.path.display().to_string(),
+                        sha256: file.sha256.clone(),
+                        link:   String::new()
+                    });
+                }
+
+                for link in package.links.iter() {
+                    txn.stage_link(&prefix.join(&link.path), &link.target)?;
+
+                    files.push(parser::ManifestFile {
+                        path:   link.path.clone(),
+                        sha256: String::new(),
+                        link:   link.target.clone()
+                    });
+                }
+
+                // a package that is installed without a file manifest was
+                // installed before manifests were recorded, see autoremove()
+                let dependency = !explicit && match old.get(&pkg.name) {
+                    Some(old) => old.dependency,
+                    None      => !pkgs.contains_key(&pkg.name)
+                };
+
+                manifests.push(parser::Manifest {
+                    name:    pkg.name.clone(),
+                    version: pkg.version.clone(),
+                    prefix:  prefix.display().to_string(),
+                    dependency,
+                    files
+                });
+
+                let previous = old.remove(&pkg.name);
+                pkgs.insert(pkg.name.clone(), *pkg);
+                previous
+            },
+            Step::Remove(name) => {
+                if pkgs.remove(&name).is_none() {
+                    return Err(Error::Install(InstallError::NotInstalled(name)));
+                }
+
+                if old.contains_key(&name) {
+                    dropped.push(name.clone());
+                } else {
+                    println!("No file manifest for {}, removing it from the package list only", name);
+                }
+
+                old.remove(&name)
+            },
+            Step::Mark { name, explicit } => {
+                marks.push((name, explicit));
+                None
+            }
+        };
+
+        // files of the previous version, the ones that are still part of the
+        // package are replaced instead of removed
+        if let Some(manifest) = previous {
+            for file in manifest.files {
+                removed.push((PathBuf::from(&manifest.prefix), file));
+            }
+        }
+    }
+
+    for (name, explicit) in marks {
+        if !pkgs.contains_key(&name) {
+            return Err(Error::Install(InstallError::NotInstalled(name)));
+        }
+
+        match manifests.iter_mut().find(|manifest| manifest.name == name) {
+            Some(manifest) => manifest.dependency = !explicit,
+            None           => match old.get(&name) {
+                Some(manifest) => manifests.push(parser::Manifest { dependency: !explicit, ..manifest.clone() }),
+                None           => println!("No file manifest for {}, it stays explicitly installed", name)
+            }
+        }
+    }
+
+    for (old_prefix, file) in removed {
+        let path = old_prefix.join(&file.path);
+
+        if !owners.contains_key(&path) && unmodified(&old_prefix, &file) {
+            txn.remove(&old_prefix, &path);
+        }
+    }
+
+    txn.commit(pkgs, manifests, dropped)?;
+
+    // the transaction is already committed, a missing generation is not fatal
+    if let Err(err) = history::record() {
+        println!("Warning: failed to record generation: {}", err);
+    }
+
+    Ok(())
+}
+
+// Remove package `name`
+//
+// The removal is refused if other installed packages depend on `name` unless
+// `cascade` is set, in which case those packages are removed as well.
+// Returns the names of the removed packages
+pub fn remove_packages(name: &str, cascade: bool) -> Result<Vec<String>, Error> {
+    let pkgs = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+
+    if !pkgs.contains_key(name) {
+        return Err(Error::Install(InstallError::NotInstalled(name.to_string())));
+    }
+
+    let mut removed = resolve::dependents(&[name.to_string()], &pkgs);
+
+    if !removed.is_empty() && !cascade {
+        return Err(Error::Install(InstallError::RequiredBy {
+            name:       name.to_string(),
+            dependents: removed
+        }));
+    }
+
+    removed.push(name.to_string());
+
+    let steps = removed.iter().map(|pkg| Step::Remove(pkg.clone())).collect();
+    apply(steps, &prefix(&settings::get_settings()?), false)?;
+    Ok(removed)
+}
+
+// Remove the packages that were installed as dependencies and that are no
+// longer needed by any explicitly installed package
+//
+// Packages without a file manifest are considered explicitly installed.
+// Returns the names of the removed packages
+pub fn autoremove() -> Result<Vec<String>, Error> {
+    let pkgs = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let dependencies: Vec<String> = parser::get_manifests()?
+        .into_iter()
+        .filter(|manifest| manifest.dependency)
+        .map(|manifest| manifest.name)
+        .collect();
+
+    let explicit: Vec<String> = pkgs
+        .keys()
+        .filter(|name| !dependencies.contains(name))
+        .cloned()
+        .collect();
+
+    let orphans = resolve::orphans(&pkgs, &explicit);
+
+    if !orphans.is_empty() {
+        let steps = orphans.iter().map(|pkg| Step::Remove(pkg.clone())).collect();
+        apply(steps, &prefix(&settings::get_settings()?), false)?;
+    }
+
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Install package `name` version `version` with a single file bin/hello
+    // to the prefix of `home`
+    fn install(home: &Path, name: &str, version: &str, overwrite: bool) -> Result<(), Error> {
+        let contents = format!("{} {}", name, version);
+        let package  = testing::package(home, name, version, &[("bin/hello", &contents)]);
+
+        apply(vec![Step::Install {
+            pkg:      Box::new(testing::pkginfo(name, version)),
+            package,
+            explicit: true
+        }], home.join("prefix").to_str().unwrap(), overwrite)
+    }
+
+    #[test]
+    fn update_keeps_packages_without_manifest_explicit() {
+        let home     = testing::home();
+        let prefix   = home.join("prefix");
+        let mut pkgs = HashMap::new();
+
+        // installed before file manifests were recorded
+        pkgs.insert("legacy".to_string(), testing::pkginfo("legacy", "1.0"));
+        parser::save_pkgs(&parser::expand("PKGLIST.toml"), pkgs).unwrap();
+
+        let package = testing::package(&home, "legacy", "1.1", &[("bin/legacy", "echo 1.1")]);
+
+        apply(vec![Step::Install {
+            pkg:      Box::new(testing::pkginfo("legacy", "1.1")),
+            package,
+            explicit: false
+        }], prefix.to_str().unwrap(), false).unwrap();
+
+        assert!(!parser::get_manifest("legacy").unwrap().dependency);
+        assert!(autoremove().unwrap().is_empty());
+        assert!(prefix.join("bin/legacy").exists());
+    }
+
+    #[test]
+    fn new_dependencies_are_autoremoved() {
+        let home    = testing::home();
+        let prefix  = home.join("prefix");
+        let package = testing::package(&home, "libfoo", "1.0", &[("lib/libfoo.so", "foo")]);
+
+        apply(vec![Step::Install {
+            pkg:      Box::new(testing::pkginfo("libfoo", "1.0")),
+            package,
+            explicit: false
+        }], prefix.to_str().unwrap(), false).unwrap();
+
+        assert_eq!(autoremove().unwrap(), vec!["libfoo".to_string()]);
+        assert!(!prefix.join("lib/libfoo.so").exists());
+    }
+
+    #[test]
+    fn unowned_file_is_not_overwritten() {
+        let home   = testing::home();
+        let prefix = home.join("prefix");
+
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin/hello"), "mine").unwrap();
+
+        assert!(matches!(
+            install(&home, "hello", "1.0", false),
+            Err(Error::Install(InstallError::FileConflict { owner: None, .. }))
+        ));
+        assert_eq!(fs::read_to_string(prefix.join("bin/hello")).unwrap(), "mine");
+
+        install(&home, "hello", "1.0", true).unwrap();
+        assert_eq!(fs::read_to_string(prefix.join("bin/hello")).unwrap(), "hello 1.0");
+
+        // the file is now owned by the package and is replaced by its updates
+        install(&home, "hello", "1.1", false).unwrap();
+        assert_eq!(fs::read_to_string(prefix.join("bin/hello")).unwrap(), "hello 1.1");
+    }
+
+    #[test]
+    fn file_of_other_package_is_not_overwritten() {
+        let home = testing::home();
+
+        install(&home, "hello", "1.0", false).unwrap();
+
+        assert!(matches!(
+            install(&home, "other", "1.0", true),
+            Err(Error::Install(InstallError::FileConflict { owner: Some(owner), .. })) if owner == "hello"
+        ));
+        assert_eq!(fs::read_to_string(home.join("prefix/bin/hello")).unwrap(), "hello 1.0");
+    }
+}
@@ -1,14 +1,18 @@
-extern crate ring;
-extern crate untrusted;
-
 use serde::{Serialize, Deserialize};
 use ipfs_api::IpfsClient;
-use std::io::Write;
-use futures::TryStreamExt;
+use futures::StreamExt;
+use std::fmt;
+use std::fs;
 use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::Error;
+use crate::cache;
 use crate::parser;
+use crate::settings;
+use crate::store;
+use crate::trust;
 use sha2::{Sha256, Digest};
-use ring::signature;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum IPFSError {
@@ -19,81 +23,142 @@ pub enum IPFSError {
     UnableToConnect,
     NewerExists,
     ChecksumMismatch,
-    SignatureMismatch
+    SignatureMismatch,
+
+    // the package is larger than its signed size
+    TooLarge,
+
+    // the signed metadata of the package doesn't contain its size
+    MissingSize,
+
+    // the package is signed by a key that may not publish it
+    Unauthorized,
+
+    // the package has fewer signatures by authorized keys than required
+    MissingSignatures
+}
+
+impl fmt::Display for IPFSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            IPFSError::Success           => "success",
+            IPFSError::Unknown           => "unknown error",
+            IPFSError::NotFound          => "not found",
+            IPFSError::AlreadyExists     => "already exists",
+            IPFSError::UnableToConnect   => "unable to connect",
+            IPFSError::NewerExists       => "a newer version is already installed",
+            IPFSError::ChecksumMismatch  => "checksum mismatch",
+            IPFSError::SignatureMismatch => "invalid signature",
+            IPFSError::TooLarge          => "package is larger than its signed size",
+            IPFSError::MissingSize       => "package size is not signed",
+            IPFSError::Unauthorized      => "signer is not authorized to publish the package",
+            IPFSError::MissingSignatures => "package doesn't have enough signatures by authorized keys"
+        };
+
+        write!(f, "{}", msg)
+    }
 }
 
 // Publish/Subscribe Topics (PST)
-pub static PST_PACKAGE:       &'static str = "pkgman_sub_query";
-pub static PST_PACKAGE_QUERY: &'static str = "pkgman_sub_query_response";
-pub static PST_KEYRING:       &'static str = "pkgman_sub_keyring_query";
-pub static PST_KEYRING_QUERY: &'static str = "pkgman_sub_keyring";
+pub static PST_KEYRING:       &str = "pkgman_sub_keyring_query";
+pub static PST_KEYRING_QUERY: &str = "pkgman_sub_keyring";
+pub static PST_BATCH:         &str = "pkgman_sub_batch_response";
+pub static PST_BATCH_QUERY:   &str = "pkgman_sub_batch_query";
 
 pub fn get_client() -> IpfsClient {
-    return IpfsClient::default();
+    IpfsClient::default()
+}
+
+pub async fn upload(store: &dyn store::ContentStore, path: &Path) -> Result<String, Error> {
+    Ok(store.add(path).await?)
 }
 
-pub async fn upload(path: &str) -> Result<String, IPFSError> {
+// Fetch the contents of `pkg` to the download cache (see cache.rs)
+//
+// The signature of the metadata is verified first, so that the signed size
+// can be trusted. If the package is in the cache and its contents still match
+// the metadata, the cached package is used. Otherwise the contents are hashed
+// while they're written to a temporary file and the download is aborted as
+// soon as it exceeds the size. The file is moved into the cache only if its
+// checksum matches the metadata. Returns the path of the package in the cache.
+// Entries without a size are accepted only with `allow_legacy_signatures`
+pub async fn download(store: &dyn store::ContentStore, pkg: &parser::PkgInfo) -> Result<PathBuf, Error> {
+    let settings = settings::get_settings()?;
+
+    if let Err(err) = trust::load()?.verify_pkginfo(pkg, settings.allow_legacy_signatures) {
+        println!("Failed to verify signature!");
+        return Err(Error::IPFS(err));
+    }
+
+    if pkg.size == 0 && !settings.allow_legacy_signatures {
+        return Err(Error::IPFS(IPFSError::MissingSize));
+    }
+
+    let path = match cache::blob_path(&pkg.ipfs) {
+        Some(path) => path,
+        None       => return Err(Error::IPFS(IPFSError::NotFound))
+    };
+
+    if let Some(path) = cache::lookup(pkg) {
+        return Ok(path);
+    }
 
-    let client = IpfsClient::default();
-    let file = File::open(path).unwrap();
+    let temp = path.with_file_name(format!(".{}.{}.part", pkg.ipfs, std::process::id()));
+    let write_error = || Error::Parser(parser::ParserError::WriteError(temp.display().to_string()));
 
-    match client.add(file).await {
-        Ok(file) => return Ok(file.hash),
-        Err(err) => {
-            println!("Failed to add file: {:#?}", err);
-            return Err(IPFSError::Unknown);
+    if let Some(dir) = path.parent() {
+        if fs::create_dir_all(dir).is_err() {
+            return Err(write_error());
         }
     }
-}
 
-pub async fn download(pkg: &parser::PkgInfo) -> Result<(), IPFSError> {
-    let client = IpfsClient::default();
-
-    match client
-        .cat(&pkg.ipfs)
-        .map_ok(|chunk| chunk.to_vec())
-        .try_concat()
-        .await
-    {
-        Ok(res) => {
-            // before the package is installed to the system
-            // verify that its sha256 checksum and signatures are valid
-            let mut sha256 = Sha256::new();
-            sha256.update(&res);
-            let digest = sha256.finalize();
-            let sig = base64::decode(&pkg.signature).unwrap();
-
-            if pkg.sha256 != format!("{:x}", digest) {
-                return Err(IPFSError::ChecksumMismatch);
+    let mut file = match File::create(&temp) {
+        Ok(file) => file,
+        Err(_)   => return Err(write_error())
+    };
+
+    let mut sha256 = Sha256::new();
+    let mut size   = 0u64;
+    let mut chunks = store.get(&pkg.ipfs);
+
+    let res: Result<(), Error> = async {
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+
+            size += chunk.len() as u64;
+
+            if pkg.size > 0 && size > pkg.size {
+                return Err(Error::IPFS(IPFSError::TooLarge));
             }
 
-            for key in parser::get_pubkeys().unwrap() {
-                let pbkey = signature::UnparsedPublicKey::new(
-                    &signature::ED25519,
-                    base64::decode(&key).unwrap()
-                );
-
-                match pbkey.verify(&pkg.sha256.as_bytes(), sig.as_ref()) {
-                    Ok(_) => {
-                        File::create(format!("{}/{}", "packages", pkg.name))
-                            .unwrap()
-                            .write_all(&res)
-                            .unwrap();
-
-                        return Ok(())
-                    },
-                    // do not exit here as there may be multiple keys of which only one will
-                    // provide the correct checksum. Error is returned after the loop
-                    Err(_err) => { }
-                }
+            sha256.update(&chunk);
+
+            if file.write_all(&chunk).is_err() {
+                return Err(write_error());
             }
+        }
+
+        if file.sync_all().is_err() {
+            return Err(write_error());
+        }
 
-            println!("Failed to verify signature!");
-            return Err(IPFSError::SignatureMismatch);
+        // before the package is installed to the system
+        // verify that its sha256 checksum is valid
+        if pkg.sha256 != format!("{:x}", sha256.finalize()) {
+            return Err(Error::IPFS(IPFSError::ChecksumMismatch));
         }
-        Err(e) => {
-            println!("error getting file: {}", e);
-            return Err(IPFSError::Unknown);
+
+        match fs::rename(&temp, &path) {
+            Ok(_)  => Ok(()),
+            Err(_) => Err(Error::Parser(parser::ParserError::WriteError(path.display().to_string())))
         }
+    }.await;
+
+    if let Err(err) = res {
+        let _ = fs::remove_file(&temp);
+        return Err(err);
     }
+
+    cache::evict(settings.cache_size.saturating_mul(1024 * 1024), &path);
+    Ok(path)
 }
@@ -0,0 +1,51 @@
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::Error;
+use crate::parser;
+
+// Advisory lock protecting the package database from concurrent modification
+//
+// The lock is held for as long as the value is alive and it is released when
+// the value is dropped or when the process exits
+pub struct Lock {
+    _file: File
+}
+
+// Take the lock of the pkgman state directory
+//
+// If another process holds the lock, either wait for it to be released
+// or fail with Error::Locked containing the pid of the other process
+pub fn acquire(wait: bool) -> Result<Lock, Error> {
+    let path = parser::expand("pkgman.lock");
+
+    let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
+        Ok(file) => file,
+        Err(_)   => return Err(Error::Parser(parser::ParserError::WriteError(path)))
+    };
+
+    if file.try_lock_exclusive().is_err() {
+        if !wait {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+
+            return Err(Error::Locked(pid.trim().parse().ok()));
+        }
+
+        println!("Waiting for another pkgman to finish...");
+
+        if file.lock_exclusive().is_err() {
+            return Err(Error::Parser(parser::ParserError::WriteError(path)));
+        }
+    }
+
+    // record our pid so that others can tell who holds the lock
+    let _ = file.set_len(0);
+    let _ = file.seek(SeekFrom::Start(0));
+    let _ = write!(file, "{}", std::process::id());
+    let _ = file.flush();
+
+    Ok(Lock { _file: file })
+}
@@ -1,161 +1,625 @@
 extern crate toml;
-extern crate ring;
-extern crate untrusted;
 
-use futures::StreamExt;
+use futures::{stream, StreamExt};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::time::Duration;
-use ring::signature;
+use version_compare::{CompOp, VersionCompare};
 
+use crate::Error;
 use crate::parser;
 use crate::ipfs;
+use crate::settings;
+use crate::protocol;
+use crate::store;
+use crate::transport;
+use crate::install;
+use crate::archive;
+use crate::resolve;
+use crate::trust;
+use crate::history;
 
-pub async fn query(pkg: &str) -> Result<parser::PkgInfo, ipfs::IPFSError> {
+// A package query is answered by every daemon that knows about the package.
+// Instead of trusting the first answer, collect all answers received within
+// QUERY_WINDOW, drop those whose signature can't be verified against the
+// keyring and pick the highest version among the remaining ones
+static QUERY_WINDOW: Duration = Duration::from_secs(3);
 
-    ipfs::get_client().pubsub_pub(ipfs::PST_PACKAGE_QUERY, pkg).await.unwrap();
+struct QueryAnswer {
+    from: String,
+    pkg:  parser::PkgInfo
+}
+
+// Query package `pkg` from the network
+//
+// If no package is named `pkg`, a package that provides `pkg` is accepted instead
+pub async fn query(transport: &dyn transport::Transport, pkg: &str) -> Result<parser::PkgInfo, Error> {
+    match query_many(transport, &[pkg.to_string()]).await?.remove(pkg) {
+        Some(info) => Ok(info),
+        None       => Err(Error::IPFS(ipfs::IPFSError::NotFound))
+    }
+}
+
+// Query packages `names` from the network with a single batch query
+//
+// Returns the selected answer for each name that was found, see query()
+pub async fn query_many(
+    transport: &dyn transport::Transport,
+    names: &[String]
+) -> Result<HashMap<String, parser::PkgInfo>, Error> {
+
+    let web      = trust::load()?;
+    let settings = settings::get_settings()?;
+    let deadline = tokio::time::Instant::now() + QUERY_WINDOW;
+    let payload  = protocol::BatchQuery { names: names.to_vec() };
+    let request  = protocol::request(&transport.peer_id().await, &toml::to_string(&payload).unwrap());
+    let mut sub  = transport.subscribe(ipfs::PST_BATCH).await?;
+    let mut answers: HashMap<&str, Vec<QueryAnswer>> = HashMap::new();
+
+    transport.publish(ipfs::PST_BATCH_QUERY, &protocol::encode(&request)).await?;
 
     loop {
-        match tokio::time::timeout(
-            Duration::from_secs(3),
-            ipfs::get_client().pubsub_sub(ipfs::PST_PACKAGE, false).next()).await
-        {
-            Ok(response) => {
-                match response {
-                    Some(msg) => {
-                        let ret: parser::PkgInfo = toml::from_str(
-                            std::str::from_utf8(
-                                &base64::decode(msg.unwrap().data.unwrap()).unwrap()
-                            ).unwrap()
-                        ).unwrap();
-
-                        if ret.name == pkg {
-                            return Ok(ret);
-                        }
-                    },
-                    None => {
-                        println!("None");
-                        return Err(ipfs::IPFSError::NotFound);
-                    }
-                }
-            },
-            Err(_err) => {
-                return Err(ipfs::IPFSError::NotFound);
+        let msg = match tokio::time::timeout_at(deadline, sub.next()).await {
+            Ok(Some(msg))     => msg,
+            Ok(None) | Err(_) => break
+        };
+
+        let from = msg.from;
+        let env  = match protocol::decode(&msg.data) {
+            Some(env) => env,
+            None => {
+                println!("Ignoring malformed response from {}", from);
+                continue;
+            }
+        };
+
+        // answer to someone else's query
+        if env.request_id != request.request_id {
+            continue;
+        }
+
+        let ret: protocol::BatchAnswer = match toml::from_str(&env.payload) {
+            Ok(ret) => ret,
+            Err(_)  => {
+                println!("Ignoring malformed response from {}", from);
+                continue;
+            }
+        };
+
+        for pkg in ret.packages {
+            let wanted: Vec<&str> = names
+                .iter()
+                .filter(|name| pkg.name == **name || resolve::provides(&pkg, name))
+                .map(|name| name.as_str())
+                .collect();
+
+            if wanted.is_empty() {
+                continue;
+            }
+
+            if let Err(err) = web.verify_pkginfo(&pkg, settings.allow_legacy_signatures) {
+                println!("Ignoring {} {} from {}: {}", pkg.name, pkg.version, from, err);
+                continue;
+            }
+
+            for name in wanted {
+                answers.entry(name).or_default().push(QueryAnswer { from: from.clone(), pkg: pkg.clone() });
             }
         }
     }
+
+    let mut found: HashMap<String, parser::PkgInfo> = HashMap::new();
+
+    for (name, mut answers) in answers {
+        // prefer the package itself over the packages that provide it
+        if answers.iter().any(|answer| answer.pkg.name == name) {
+            answers.retain(|answer| answer.pkg.name == name);
+        }
+
+        found.insert(name.to_string(), select_answer(answers)?);
+    }
+
+    Ok(found)
 }
 
-pub async fn update() -> Result<(), ipfs::IPFSError> {
+// Select the answer with the highest version and report the responders that disagree with it
+//
+// Answers with the same version are ordered by package name and CID so that
+// the selection doesn't depend on the order in which the answers arrived
+fn select_answer(answers: Vec<QueryAnswer>) -> Result<parser::PkgInfo, ipfs::IPFSError> {
+    let mut best: Option<&QueryAnswer> = None;
 
-    for (_, pkg) in parser::get_pkgs(&parser::expand("PKGLIST.toml")).unwrap().into_iter() {
-        match download(&pkg.name).await {
-            Ok(_) => {
-                println!("Package {} updated successfully!", pkg.name);
+    for answer in answers.iter() {
+        best = match best {
+            Some(cur) => match compare_versions(&answer.pkg.version, &cur.pkg.version) {
+                Some(Ordering::Greater) => Some(answer),
+                Some(Ordering::Equal)
+                    if (&answer.pkg.name, &answer.pkg.ipfs) < (&cur.pkg.name, &cur.pkg.ipfs) => Some(answer),
+                _ => Some(cur)
             },
-            Err(err) => {
-                println!("Failed to update package {}: {:#?}", pkg.name, err);
-            }
+            None => Some(answer)
+        };
+    }
+
+    let best = match best {
+        Some(best) => best,
+        None       => return Err(ipfs::IPFSError::NotFound)
+    };
+
+    for answer in answers.iter() {
+        if answer.pkg.version != best.pkg.version {
+            println!("{} answered {} {} (newest is {})",
+                     answer.from, answer.pkg.name, answer.pkg.version, best.pkg.version);
+        } else if answer.pkg.sha256 != best.pkg.sha256 || answer.pkg.ipfs != best.pkg.ipfs {
+            println!("{} answered {} {} with different contents ({}) than {} ({})",
+                     answer.from, answer.pkg.name, answer.pkg.version, answer.pkg.ipfs,
+                     best.from, best.pkg.ipfs);
         }
     }
 
-    Ok(())
+    Ok(best.pkg.clone())
 }
 
-pub async fn download(name: &str) -> Result<(), ipfs::IPFSError> {
+// Compare two version strings, returns None if either of them can't be parsed
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    match VersionCompare::compare(a, b) {
+        Ok(CompOp::Lt) => Some(Ordering::Less),
+        Ok(CompOp::Eq) => Some(Ordering::Equal),
+        Ok(CompOp::Gt) => Some(Ordering::Greater),
+        _              => None
+    }
+}
+
+// Update all installed packages in a single transaction
+//
+// Files in the prefix that no package installed are replaced only if
+// `overwrite` is set, see install::apply()
+pub async fn update(
+    store: &dyn store::ContentStore,
+    transport: &dyn transport::Transport,
+    allow_downgrade: bool,
+    overwrite: bool
+) -> Result<(), Error> {
 
-    let mut pkgs = parser::get_pkgs(&parser::expand("PKGLIST.toml")).unwrap();
+    let pkgs      = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let mut names = pkgs.keys().cloned().collect::<Vec<String>>();
 
-    match query(name).await {
-        Ok(pkg) => {
-            let new_pkg = pkg.clone();
+    names.sort();
 
-            match pkgs.get(name) {
-                Some(our_pkg) => {
-                    if pkg.version == our_pkg.version {
-                        return Err(ipfs::IPFSError::AlreadyExists);
+    let plan = resolve::resolve(transport, &names, &pkgs, true).await?;
+    let todo = changes(plan.install, &pkgs, allow_downgrade, false)?;
+
+    if todo.is_empty() && plan.replace.is_empty() {
+        println!("All packages are up to date");
+        return Ok(());
+    }
+
+    commit(store, todo, plan.replace, None, overwrite).await
+}
+
+// Download package `name` and the packages it depends on, install them and
+// add them to PKGLIST.toml
+//
+// If a package is already installed, the version received from the network
+// must be newer than the installed one. An older version (or a version that
+// can't be compared to the installed one) is rejected with NewerExists unless
+// `allow_downgrade` is set, as otherwise a stale or malicious daemon could
+// serve an old but validly signed package as an "update". Files in the prefix
+// that no package installed are replaced only if `overwrite` is set
+pub async fn download(
+    store: &dyn store::ContentStore,
+    transport: &dyn transport::Transport,
+    name: &str,
+    allow_downgrade: bool,
+    overwrite: bool
+) -> Result<(), Error> {
+
+    let pkgs = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let plan = resolve::resolve(transport, &[name.to_string()], &pkgs, false).await?;
+    let todo = changes(plan.install, &pkgs, allow_downgrade, true)?;
+
+    if todo.is_empty() {
+        return Err(Error::IPFS(ipfs::IPFSError::AlreadyExists));
+    }
+
+    commit(store, todo, plan.replace, Some(name), overwrite).await
+}
+
+// Select the packages of `resolved` that differ from the installed ones
+//
+// Packages that would be downgraded are refused, with NewerExists if `strict`
+// is set or by skipping them otherwise, unless `allow_downgrade` is set
+fn changes(
+    resolved: Vec<parser::PkgInfo>,
+    installed: &HashMap<String, parser::PkgInfo>,
+    allow_downgrade: bool,
+    strict: bool
+) -> Result<Vec<parser::PkgInfo>, Error> {
+
+    let mut todo: Vec<parser::PkgInfo> = Vec::new();
+
+    for pkg in resolved {
+        if let Some(our_pkg) = installed.get(&pkg.name) {
+            match compare_versions(&pkg.version, &our_pkg.version) {
+                Some(Ordering::Greater) => { },
+                Some(Ordering::Equal)   => continue,
+                _ if allow_downgrade    => { },
+                _ => {
+                    println!("Refusing to replace {} {} with {}",
+                             pkg.name, our_pkg.version, pkg.version);
+
+                    if strict {
+                        return Err(Error::IPFS(ipfs::IPFSError::NewerExists));
                     }
-                },
-                None => { }
+                    continue;
+                }
             }
+        }
+
+        todo.push(pkg);
+    }
+
+    Ok(todo)
+}
+
+// Download and verify packages `pkgs`
+//
+// At most `parallel_downloads` packages (see settings.rs) are downloaded at
+// the same time. The results are returned in the order of `pkgs`
+pub async fn fetch(
+    store: &dyn store::ContentStore,
+    pkgs: &[parser::PkgInfo]
+) -> Result<Vec<Result<archive::Package, Error>>, Error> {
 
-            match ipfs::download(&pkg).await {
-                Ok(_) => {
-                    pkgs.insert(pkg.name, new_pkg);
-                    parser::save_pkgs(&parser::expand("PKGLIST.toml"), pkgs);
-                    return Ok(());
-                },
-                Err(err) => return Err(err)
+    let limit = settings::get_settings()?.parallel_downloads.max(1);
+
+    Ok(stream::iter(pkgs)
+        .map(|pkg| async move {
+            let path = ipfs::download(store, pkg).await?;
+
+            Ok(archive::open(&path, &pkg.name, &pkg.version)?)
+        })
+        .buffered(limit)
+        .collect()
+        .await)
+}
+
+// Download the packages of `todo` and install them in a single transaction
+// that also removes the installed packages of `replace`
+//
+// All packages are downloaded and verified before anything is installed, and
+// nothing is installed if any of them fails. Only the package `requested` by
+// the user, if any, is recorded as an explicitly installed package. The result
+// of each package is printed once the transaction is done
+async fn commit(
+    store: &dyn store::ContentStore,
+    todo: Vec<parser::PkgInfo>,
+    replace: Vec<String>,
+    requested: Option<&str>,
+    overwrite: bool
+) -> Result<(), Error> {
+
+    println!("Downloading {} package(s)", todo.len());
+
+    let installed = parser::get_pkgs(&parser::expand("PKGLIST.toml"))?;
+    let fetched   = fetch(store, &todo).await?;
+    let mut steps: Vec<install::Step> = Vec::new();
+    let mut summary: Vec<(String, String)> = Vec::new();
+    let mut failed: Option<Error> = None;
+
+    for (pkg, res) in todo.into_iter().zip(fetched) {
+        let label = format!("{} {}", pkg.name, pkg.version);
+
+        let package = match res {
+            Ok(package) => package,
+            Err(err) => {
+                summary.push((label, format!("failed: {}", err)));
+                failed = failed.or(Some(err));
+                continue;
             }
-        },
-        Err(err) => return Err(err)
+        };
+
+        let status = match installed.get(&pkg.name) {
+            Some(old) => format!("updated from {}", old.version),
+            None      => String::from("installed")
+        };
+
+        let explicit = match requested {
+            Some(name) => pkg.name == name || resolve::provides(&pkg, name),
+            None       => false
+        };
+
+        summary.push((label, status));
+        steps.push(install::Step::Install { pkg: Box::new(pkg), package, explicit });
+    }
+
+    for old in replace {
+        summary.push((old.clone(), String::from("removed, replaced by the new packages")));
+        steps.push(install::Step::Remove(old));
     }
+
+    let res = match failed {
+        Some(err) => Err(err),
+        None      => install::apply(steps, &install::prefix(&settings::get_settings()?), overwrite)
+    };
+
+    print_summary(&summary, res.is_ok());
+    res
 }
 
-pub async fn update_keyring() -> Result<(), ipfs::IPFSError> {
+fn print_summary(summary: &[(String, String)], committed: bool) {
+    let width = summary.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
 
-    ipfs::get_client().pubsub_pub(ipfs::PST_KEYRING_QUERY, "update").await.unwrap();
+    if !committed {
+        println!("Nothing was changed:");
+    }
+
+    for (label, status) in summary {
+        // a successful download is not an installation if the transaction failed
+        if !committed && !status.starts_with("failed") {
+            println!("  {:width$}  skipped", label, width = width);
+        } else {
+            println!("  {:width$}  {}", label, status, width = width);
+        }
+    }
+}
+
+// Fetch the latest keyring snapshot from the network
+//
+// Snapshots that are not signed by a trust anchor of pkgman.toml, that have
+// expired or that are older than the current keyring are ignored (see
+// trust::check_snapshot), and the snapshot with the largest serial of the
+// answers received within QUERY_WINDOW replaces the current keyring. The
+// current keyring is kept if there's no valid snapshot
+pub async fn update_keyring(transport: &dyn transport::Transport) -> Result<(), Error> {
+
+    let settings = settings::get_settings()?;
+
+    // there's nothing to verify the keyring against
+    trust::anchors(&settings)?;
+
+    let current  = parser::get_keyring()?;
+    let deadline = tokio::time::Instant::now() + QUERY_WINDOW;
+    let request  = protocol::request(&transport.peer_id().await, "update");
+    let mut sub  = transport.subscribe(ipfs::PST_KEYRING).await?;
+    let mut latest: Option<parser::KeyringConfig> = None;
+    let mut error: Option<trust::TrustError> = None;
+
+    transport.publish(ipfs::PST_KEYRING_QUERY, &protocol::encode(&request)).await?;
 
     loop {
-        match tokio::time::timeout(
-            Duration::from_secs(3),
-            ipfs::get_client().pubsub_sub(ipfs::PST_KEYRING, false).next()).await
-        {
-            Ok(response) => {
-                match response {
-                    Some(msg) => {
-                        let trusted_ascii = "3c2PgNisX4vOumXAYVETS1aDKLHYEuhKSo7i1xnwr2Y=";
-                        let trusted = base64::decode(trusted_ascii).unwrap();
-                        let mut accepted: Vec<parser::KeyringEntry> = Vec::new();
-
-                        let signers: parser::KeyringConfig = toml::from_str(
-                            std::str::from_utf8(
-                                &base64::decode(msg.unwrap().data.unwrap()).unwrap()
-                            ).unwrap()
-                        ).unwrap();
-
-                        for signer in signers.signers {
-                            // as a malicious third-party might want to DoS the system, he may
-                            // distribute incorrect KEYRING.toml file that contains only invalid
-                            // entries which prevents the user from downloading any packages as all
-                            // signature verifications fail.
-                            //
-                            // To prevent this from happening, always add the initial node's
-                            // information to KEYRING.toml so there's always at least one public
-                            // key that can be used to verify the packages
-                            if signer.key == trusted_ascii {
-                                continue;
-                            }
-
-                            let pbkey = signature::UnparsedPublicKey::new(&signature::ED25519, &trusted);
-                            let sig = base64::decode(&signer.signature).unwrap();
-
-                            match pbkey.verify(&signer.key.as_bytes(), sig.as_ref()) {
-                                Ok(_) => {
-                                    println!("{} ({}) accepted!", signer.name, signer.email);
-                                    accepted.push(signer);
-                                },
-                                Err(_err) => {
-                                    println!("{} ({}) rejected!", signer.name, signer.email);
-                                }
-                            }
-                        }
-
-                        if accepted.len() == 0 {
-                            parser::save_keyring_default();
-                        } else {
-                            parser::save_keyring(accepted);
-                        }
-
-                        return Ok(());
-                    },
-                    None => {
-                        println!("None");
-                        return Err(ipfs::IPFSError::NotFound);
-                    }
-                }
-            },
-            Err(_err) => {
-                return Err(ipfs::IPFSError::NotFound);
+        let msg = match tokio::time::timeout_at(deadline, sub.next()).await {
+            Ok(Some(msg))     => msg,
+            Ok(None) | Err(_) => break
+        };
+
+        let from = msg.from;
+        let env  = match protocol::decode(&msg.data) {
+            Some(env) => env,
+            None      => continue
+        };
+
+        if env.request_id != request.request_id {
+            continue;
+        }
+
+        let keyring: parser::KeyringConfig = match toml::from_str(&env.payload) {
+            Ok(keyring) => keyring,
+            Err(_)      => {
+                println!("Ignoring malformed keyring from {}", from);
+                continue;
             }
+        };
+
+        if let Err(err) = trust::check_snapshot(&settings, &keyring, &current) {
+            println!("Ignoring keyring from {}: {}", from, err);
+            error = Some(err);
+            continue;
+        }
+
+        if latest.as_ref().map(|latest| keyring.serial > latest.serial).unwrap_or(true) {
+            latest = Some(keyring);
         }
     }
+
+    let keyring = match (latest, error) {
+        (Some(keyring), _) => keyring,
+        (None, Some(err))  => return Err(Error::Trust(err)),
+        (None, None)       => return Err(Error::IPFS(ipfs::IPFSError::NotFound))
+    };
+
+    println!("Keyring snapshot {}, expires {}", keyring.serial, history::format_time(keyring.expires));
+
+    let web = trust::Web::new(&settings, keyring.clone())?;
+
+    for signer in web.entries() {
+        // the anchors are trusted through pkgman.toml
+        if web.is_anchor(&signer.key) {
+            continue;
+        }
+
+        match web.trusted(&signer.key) {
+            true  => println!("{} ({}) accepted!", signer.name, signer.email),
+            false => println!("{} ({}) rejected!", signer.name, signer.email)
+        };
+    }
+
+    for rev in web.revocations() {
+        println!("{} revoked since {}", web.name(&rev.key), history::format_time(rev.timestamp));
+    }
+
+    Ok(parser::save_keyring(&keyring)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use crate::daemon;
+    use crate::testing;
+    use crate::transport::Transport;
+    use ring::signature::Ed25519KeyPair;
+    use sha2::{Sha256, Digest};
+    use std::fs;
+
+    // daemon and clients connected through a MemoryBus, sharing the home
+    // directory and a DirStore
+    struct Network {
+        home:   testing::Home,
+        bus:    transport::MemoryBus,
+        anchor: Ed25519KeyPair,
+        maint:  Ed25519KeyPair
+    }
+
+    // Trust a new trust anchor, and let the daemon serve a keyring snapshot
+    // in which the anchor certifies a maintainer of the "hello*" packages
+    fn network() -> Network {
+        let home = testing::home();
+        let (anchor, anchor_key) = testing::keypair();
+        let (maint, maint_key)   = testing::keypair();
+
+        fs::write(parser::expand("pkgman.toml"), format!(
+            "content_store = \"{}\"\nprefix = \"{}\"\n",
+            home.join("store").display(),
+            home.join("prefix").display()
+        )).unwrap();
+
+        parser::save_trust_anchors(&[trust::anchor(&anchor_key).unwrap()]).unwrap();
+        parser::save_pkgs(&parser::expand("PKGLIST_bootstrap.toml"), HashMap::new()).unwrap();
+        parser::save_keyring(&parser::KeyringConfig::default()).unwrap();
+
+        let mut keyring = parser::KeyringConfig {
+            serial:  1,
+            expires: crypto::now() + 3600,
+            signers: vec![testing::entry("alice", &maint_key, &[&anchor])],
+            owners:  vec![parser::Ownership { key: maint_key, packages: vec!["hello*".to_string()] }],
+            ..parser::KeyringConfig::default()
+        };
+
+        crypto::sign_keyring(&anchor, &mut keyring).unwrap();
+        fs::write(parser::expand("KEYRING_bootstrap.toml"), toml::to_string(&keyring).unwrap()).unwrap();
+
+        Network { home, bus: transport::MemoryBus::new(), anchor, maint }
+    }
+
+    fn dir_store(net: &Network) -> store::DirStore {
+        store::DirStore::new(net.home.join("store").to_str().unwrap())
+    }
+
+    // Build, upload and sign package `name` version `version` depending on
+    // `depends` and containing `files`, and add it to the package list served by the daemon
+    async fn publish(
+        net: &Network,
+        keypair: &Ed25519KeyPair,
+        name: &str,
+        version: &str,
+        depends: &[&str],
+        files: &[(&str, &str)]
+    ) {
+        let mut pkg = testing::pkginfo(name, version);
+        let package = testing::package(&testing::temp_dir(), name, version, files);
+        let data    = fs::read(&package.path).unwrap();
+
+        pkg.depends = depends.iter().map(|dep| dep.to_string()).collect();
+        pkg.sha256  = format!("{:x}", Sha256::digest(&data));
+        pkg.size    = data.len() as u64;
+        pkg.ipfs    = ipfs::upload(&dir_store(net), &package.path).await.unwrap();
+        crypto::sign_pkginfo(keypair, &mut pkg).unwrap();
+
+        let list     = parser::expand("PKGLIST_bootstrap.toml");
+        let mut pkgs = parser::get_pkgs(&list).unwrap();
+
+        pkgs.insert(pkg.name.clone(), pkg);
+        parser::save_pkgs(&list, pkgs).unwrap();
+    }
+
+    // Run a daemon serving the current package list
+    async fn start_daemon(net: &Network) {
+        let store     = dir_store(net);
+        let transport = net.bus.connect("daemon");
+
+        actix_rt::spawn(async move {
+            daemon::daemon(&store, &transport).await.unwrap();
+        });
+
+        // let the daemon subscribe before the clients publish their queries
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+
+    fn installed(net: &Network, file: &str) -> Option<String> {
+        fs::read_to_string(net.home.join("prefix").join(file)).ok()
+    }
+
+    #[actix_rt::test]
+    async fn download_and_update() {
+        let net = network();
+
+        publish(&net, &net.maint, "hello-lib", "1.0", &[], &[("lib/libhello.so", "lib")]).await;
+        publish(&net, &net.maint, "hello", "1.0", &["hello-lib >= 1.0"], &[("bin/hello", "echo hi")]).await;
+        start_daemon(&net).await;
+
+        let client = net.bus.connect("client");
+        let store  = dir_store(&net);
+
+        update_keyring(&client).await.unwrap();
+        assert_eq!(parser::get_keyring().unwrap().serial, 1);
+
+        download(&store, &client, "hello", false, false).await.unwrap();
+
+        assert_eq!(installed(&net, "bin/hello"), Some("echo hi".to_string()));
+        assert_eq!(installed(&net, "lib/libhello.so"), Some("lib".to_string()));
+        assert!(!parser::get_manifest("hello").unwrap().dependency);
+        assert!(parser::get_manifest("hello-lib").unwrap().dependency);
+
+        // a second daemon serves the update published after the download
+        publish(&net, &net.maint, "hello", "1.1", &["hello-lib >= 1.0"], &[("bin/hello", "echo hello")]).await;
+        start_daemon(&net).await;
+
+        update(&store, &client, false, false).await.unwrap();
+        assert_eq!(installed(&net, "bin/hello"), Some("echo hello".to_string()));
+        assert_eq!(parser::get_pkgs(&parser::expand("PKGLIST.toml")).unwrap()["hello"].version, "1.1");
+    }
+
+    #[actix_rt::test]
+    async fn forged_answers_are_ignored() {
+        let net = network();
+
+        publish(&net, &net.maint, "hello", "1.0", &[], &[("bin/hello", "echo hi")]).await;
+        start_daemon(&net).await;
+
+        // answers every query with a newer version signed by a key outside of the keyring
+        let (forger, _) = testing::keypair();
+        let forged      = testing::signed(&forger, "hello", "9.0");
+        let rogue       = net.bus.connect("rogue");
+        let mut queries = rogue.subscribe(ipfs::PST_BATCH_QUERY).await.unwrap();
+
+        actix_rt::spawn(async move {
+            while let Some(msg) = queries.next().await {
+                let req    = protocol::decode(&msg.data).unwrap();
+                let answer = protocol::BatchAnswer { packages: vec![forged.clone()] };
+
+                rogue.publish(
+                    ipfs::PST_BATCH,
+                    &protocol::encode(&protocol::response(&req, &toml::to_string(&answer).unwrap()))
+                ).await.unwrap();
+            }
+        });
+
+        let client = net.bus.connect("client");
+
+        update_keyring(&client).await.unwrap();
+        assert_eq!(query(&client, "hello").await.unwrap().version, "1.0");
+
+        // the maintainer may not publish glibc
+        publish(&net, &net.maint, "glibc", "2.31", &[], &[("lib/libc.so", "libc")]).await;
+        start_daemon(&net).await;
+
+        assert!(query(&client, "glibc").await.is_err());
+
+        // the trust anchor may publish any package
+        publish(&net, &net.anchor, "glibc", "2.32", &[], &[("lib/libc.so", "libc")]).await;
+        start_daemon(&net).await;
+
+        assert_eq!(query(&client, "glibc").await.unwrap().version, "2.32");
+    }
 }
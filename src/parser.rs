@@ -7,14 +7,56 @@ use std::io::prelude::*;
 use std::io::ErrorKind;
 use serde::{Serialize, Deserialize};
 use std::path::{Path, PathBuf};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+
+use crate::settings;
 
 #[derive(Debug)]
 pub enum ParserError {
     GenericError,
-    ReadError,
-    NotFoundError,
-    EmptyFileError
+    ReadError(String),
+    WriteError(String),
+    NotFoundError(String),
+    EmptyFileError(String),
+
+    // file is not valid TOML or a field has the wrong type,
+    // line is zero if the location of the error is not known
+    SyntaxError {
+        path:    String,
+        line:    usize,
+        column:  usize,
+        message: String
+    },
+
+    // entry starting at `line` doesn't have the mandatory field `field`
+    MissingField {
+        path:  String,
+        line:  usize,
+        field: &'static str
+    }
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParserError::GenericError         => write!(f, "unknown error"),
+            ParserError::ReadError(path)      => write!(f, "{}: failed to read file", path),
+            ParserError::WriteError(path)     => write!(f, "{}: failed to write file", path),
+            ParserError::NotFoundError(path)  => write!(f, "{}: file not found", path),
+            ParserError::EmptyFileError(path) => write!(f, "{}: file has no entries", path),
+            ParserError::SyntaxError { path, line: 0, message, .. } => {
+                write!(f, "{}: {}", path, message)
+            },
+            ParserError::SyntaxError { path, line, column, message } => {
+                write!(f, "{}:{}:{}: {}", path, line, column, message)
+            },
+            ParserError::MissingField { path, line, field } => {
+                write!(f, "{}:{}: entry is missing field \"{}\"", path, line, field)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,48 +65,251 @@ pub struct PkgInfo {
     pub version:   String,
     pub sha256:    String,
     pub ipfs:      String,
+    pub signature: String,
+    // key id of the maintainer who signed this entry and the time of signing
+    // (seconds since UNIX epoch). Both are empty for legacy entries where only
+    // the sha256 digest was signed
+    #[serde(default)]
+    pub signer:    String,
+    #[serde(default)]
+    pub timestamp: u64,
+
+    // size of the package archive in bytes, downloads larger than this are
+    // aborted. Zero for entries created before the size was signed
+    #[serde(default)]
+    pub size:      u64,
+
+    // relations to other packages, each entry is a package name optionally
+    // followed by a version constraint, e.g., "glibc >= 2.31" (see resolve.rs)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends:   Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provides:  Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaces:  Vec<String>,
+
+    // signatures of the entry by other maintainers, required for packages
+    // that need more than one signature (see trust::Web::verify_pkginfo)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cosignatures: Vec<Cosignature>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Cosignature {
+    // key id of the co-signing maintainer and the time of signing
+    pub signer:    String,
+    pub timestamp: u64,
     pub signature: String
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Snapshot of the keyring, signed by a trust anchor (see trust::check_snapshot)
+//
+// The serial of each new snapshot is larger than the serial of the previous one,
+// and the snapshot isn't accepted from the network after it expires
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct KeyringConfig {
-    pub signers: Vec<KeyringEntry>
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub serial:      u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub expires:     u64,
+
+    // key id of the signing trust anchor
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signer:      String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature:   String,
+    pub signers:     Vec<KeyringEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revocations: Vec<Revocation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub owners:      Vec<Ownership>
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Package names the maintainer of `key` may publish, a name ending
+// with `*` stands for all package names starting with the rest of it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Ownership {
+    pub key:      String,
+    pub packages: Vec<String>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct KeyringEntry {
     pub name:      String,
     pub email:     String,
     pub key:       String,
+
+    // signature of the key by a trust anchor, used by keyrings created
+    // before certifications were added
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub signature: String,
+
+    // packages signed by the key are accepted only if they were signed at or after
+    // `valid_from` and before `valid_until`, zero if there's no such limit
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub valid_from:  u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub valid_until: u64,
+
+    // signatures of the entry by other keys (see trust.rs)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub certifications: Vec<Certification>,
+
+    // statement of the previous key of the maintainer that the maintainer
+    // moved to this key, signed by the previous key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<Rotation>
+}
+
+// Statement that the maintainer of `key` moved to the key of the keyring entry
+// at `timestamp`, signed by `key`. The entry is trusted through the certifications
+// of `key` and `key` is no longer accepted for anything signed at or after
+// `timestamp` (see trust.rs)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Rotation {
+    pub key:       String,
+    pub timestamp: u64,
+    pub signature: String
+}
+
+// Statement that `key` must not be trusted anymore, signed by the key itself
+// or by a trust anchor (see trust.rs). `timestamp` is the time the key was
+// revoked or compromised at, which is only informational
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Revocation {
+    pub key:       String,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub reason:    String,
+
+    // key id of the revoking key
+    pub signer:    String,
+    pub signature: String
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Certification {
+    // key id of the certifying key
+    pub signer:    String,
     pub signature: String
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
-    global_str: Option<String>,
     packages:   Option<Vec<PkgInfoInternal>>,
 }
 
 #[derive(Debug, Deserialize)]
 struct PkgInfoInternal {
-    name:      Option<String>,
-    version:   Option<String>,
-    sha256:    Option<String>,
-    ipfs:      Option<String>,
-    signature: Option<String>
+    name:         Option<String>,
+    version:      Option<String>,
+    sha256:       Option<String>,
+    ipfs:         Option<String>,
+    signature:    Option<String>,
+    signer:       Option<String>,
+    timestamp:    Option<u64>,
+    size:         Option<u64>,
+    depends:      Option<Vec<String>>,
+    conflicts:    Option<Vec<String>>,
+    provides:     Option<Vec<String>>,
+    replaces:     Option<Vec<String>>,
+    cosignatures: Option<Vec<Cosignature>>
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct KeyringConfigInternal {
-    signers: Option<Vec<KeyringEntryInternal>>
+    serial:      Option<u64>,
+    expires:     Option<u64>,
+    signer:      Option<String>,
+    signature:   Option<String>,
+    signers:     Option<Vec<KeyringEntryInternal>>,
+    revocations: Option<Vec<Revocation>>,
+    owners:      Option<Vec<Ownership>>
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct KeyringEntryInternal {
-    name:      Option<String>,
-    email:     Option<String>,
-    key:       Option<String>,
-    signature: Option<String>
+    name:           Option<String>,
+    email:          Option<String>,
+    key:            Option<String>,
+    signature:      Option<String>,
+    valid_from:     Option<u64>,
+    valid_until:    Option<u64>,
+    certifications: Option<Vec<Certification>>,
+    rotation:       Option<Rotation>
+}
+
+// List of files installed by a package, stored in ~/.config/pkgman/manifests/<name>.toml
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Manifest {
+    pub name:       String,
+    pub version:    String,
+    pub prefix:     String,
+
+    // package was installed only as a dependency of another package
+    // and it can be removed by --autoremove once nothing depends on it
+    #[serde(default)]
+    pub dependency: bool,
+    #[serde(default)]
+    pub files:      Vec<ManifestFile>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ManifestFile {
+    // path relative to the prefix
+    pub path:   String,
+    pub sha256: String,
+
+    // target of a symbolic link, sha256 is empty for links
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub link:   String
+}
+
+// Set of installed packages after a committed transaction,
+// stored in ~/.config/pkgman/generations/<number>.toml (see history.rs)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Generation {
+    pub number:       u64,
+
+    // time of the transaction, seconds since UNIX epoch
+    pub timestamp:    u64,
+
+    // names of the packages that were installed as dependencies
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub packages:     Vec<PkgInfo>
+}
+
+// Journal of an install transaction that has not been committed yet,
+// stored in ~/.config/pkgman/transaction.toml (see transaction.rs)
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Journal {
+    // contents of PKGLIST.toml before the transaction
+    pub pkglist:   Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub manifests: Vec<JournalManifest>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files:     Vec<JournalFile>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JournalManifest {
+    pub name:     String,
+
+    // contents of the manifest before the transaction, None if there was no manifest
+    pub contents: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JournalFile {
+    // absolute path of a file replaced or removed by the transaction
+    pub path:    String,
+
+    // whether the file existed before the transaction
+    pub existed: bool
 }
 
 #[derive(Serialize)]
@@ -72,61 +317,203 @@ struct ConfigWriter {
     packages: Vec<PkgInfo>
 }
 
-pub fn expand(config: &str) -> String {
-    let home  = std::env::var("HOME").unwrap();
-    let fname = PathBuf::from(format!("{}/.config/pkgman/{}", home, config));
+fn is_zero(val: &u64) -> bool {
+    *val == 0
+}
+
+thread_local! {
+    // home directory set with set_home(), if any
+    static HOME: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
 
-    if !Path::new(&fname).exists() {
-        println!("Config file not found!");
-        return String::new();
+// Use `home` instead of $HOME as the home directory of the current thread,
+// or $HOME again if it's None
+//
+// The configuration, the package database and the download cache are all
+// located in the home directory, so that several installations can be used
+// from the same process without changing its environment
+pub fn set_home(home: Option<PathBuf>) {
+    HOME.with(|cur| *cur.borrow_mut() = home);
+}
+
+// Home directory of the current thread, see set_home()
+pub fn home() -> PathBuf {
+    match HOME.with(|cur| cur.borrow().clone()) {
+        Some(home) => home,
+        None       => PathBuf::from(std::env::var("HOME").unwrap())
     }
+}
+
+pub fn expand(config: &str) -> String {
+    let fname = home().join(".config/pkgman").join(config);
 
-    return fname.into_os_string().into_string().unwrap();
+    fname.into_os_string().into_string().unwrap()
 }
 
-pub fn get_pkgs(fname: &str) -> Result<HashMap<String, PkgInfo>, ParserError> {
+fn read_file(path: &str) -> Result<String, ParserError> {
     let mut contents = String::new();
-    let mut map: HashMap<String, PkgInfo> = HashMap::new();
 
-    let mut f = match File::open(fname) {
+    let mut f = match File::open(path) {
         Ok(val)  => val,
         Err(err) => match err.kind() {
-            ErrorKind::NotFound => return Err(ParserError::NotFoundError),
-            _                   => return Err(ParserError::GenericError),
+            ErrorKind::NotFound => return Err(ParserError::NotFoundError(path.to_string())),
+            _                   => return Err(ParserError::ReadError(path.to_string())),
         }
     };
 
     match f.read_to_string(&mut contents) {
-        Ok(_)  => (),
-        Err(_) => return Err(ParserError::ReadError)
+        Ok(_)  => Ok(contents),
+        Err(_) => Err(ParserError::ReadError(path.to_string()))
+    }
+}
+
+fn parse_toml<'de, T: Deserialize<'de>>(path: &str, contents: &'de str) -> Result<T, ParserError> {
+    match toml::from_str(contents) {
+        Ok(val)  => Ok(val),
+        Err(err) => {
+            let (line, column) = err.line_col().unwrap_or((0, 0));
+
+            Err(ParserError::SyntaxError {
+                path:    path.to_string(),
+                line:    line + 1,
+                column:  column + 1,
+                message: err.to_string()
+            })
+        }
     }
+}
 
-    let config: Config = toml::from_str(&contents).unwrap();
+// Line number of the `index`th [[table]] header of `contents`
+//
+// TOML parser doesn't provide the location of the deserialized values so
+// the entries are located by finding their headers from the file
+fn entry_line(contents: &str, table: &str, index: usize) -> usize {
+    let header = format!("[[{}]]", table);
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.trim() == header)
+        .nth(index)
+        .map(|(num, _)| num + 1)
+        .unwrap_or(0)
+}
 
-    if !config.packages.is_some() {
-        return Ok(map);
+fn required<T>(val: Option<T>, path: &str, line: usize, field: &'static str) -> Result<T, ParserError> {
+    match val {
+        Some(val) => Ok(val),
+        None      => Err(ParserError::MissingField { path: path.to_string(), line, field })
     }
+}
+
+// Read package list `fname`
+//
+// If the file can't be read or parsed, the previous generation of the file
+// saved by write_file() is used instead so that a corrupted package list
+// doesn't lose the information about the installed packages
+pub fn get_pkgs(fname: &str) -> Result<HashMap<String, PkgInfo>, ParserError> {
+    match parse_pkgs(fname) {
+        Err(ParserError::NotFoundError(path)) => Err(ParserError::NotFoundError(path)),
+        Err(err) => {
+            let backup = backup_path(fname);
+
+            match parse_pkgs(&backup) {
+                Ok(map) => {
+                    println!("{}", err);
+                    println!("Using the previous version of the package list from {}", backup);
+                    Ok(map)
+                },
+                Err(_) => Err(err)
+            }
+        },
+        res => res
+    }
+}
+
+fn parse_pkgs(fname: &str) -> Result<HashMap<String, PkgInfo>, ParserError> {
+    let contents = read_file(fname)?;
+    let mut map: HashMap<String, PkgInfo> = HashMap::new();
+
+    let config: Config = parse_toml(fname, &contents)?;
+
+    let packages = match config.packages {
+        Some(packages) => packages,
+        None           => return Ok(map)
+    };
+
+    for (i, val) in packages.into_iter().enumerate() {
+        let line    = entry_line(&contents, "packages", i);
+        let pkgname = required(val.name, fname, line, "name")?;
 
-    for val in config.packages.unwrap() {
-        let pkgname = val.name.unwrap();
         map.insert(pkgname.clone(), PkgInfo {
-            name:      pkgname.clone(),
-            version:   val.version.unwrap(),
-            sha256:    val.sha256.unwrap(),
-            ipfs:      val.ipfs.unwrap_or_else(|| "".to_string()),
-            signature: val.signature.unwrap_or_else(|| "".to_string())
+            name:         pkgname,
+            version:      required(val.version, fname, line, "version")?,
+            sha256:       required(val.sha256, fname, line, "sha256")?,
+            ipfs:         val.ipfs.unwrap_or_else(|| "".to_string()),
+            signature:    val.signature.unwrap_or_else(|| "".to_string()),
+            signer:       val.signer.unwrap_or_else(|| "".to_string()),
+            timestamp:    val.timestamp.unwrap_or(0),
+            size:         val.size.unwrap_or(0),
+            depends:      val.depends.unwrap_or_default(),
+            conflicts:    val.conflicts.unwrap_or_default(),
+            provides:     val.provides.unwrap_or_default(),
+            replaces:     val.replaces.unwrap_or_default(),
+            cosignatures: val.cosignatures.unwrap_or_default()
         });
     }
 
-    return Ok(map)
+    Ok(map)
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{}.bak", path)
 }
 
-pub fn save_pkgs(path: &str, pkgs: HashMap<String, PkgInfo>) {
+// write `contents` to `path` and flush it to the disk
+fn write_synced(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut f = File::create(path)?;
+
+    f.write_all(contents)?;
+    f.sync_all()
+}
+
+// Replace the contents of `path` with `contents`
+//
+// The new contents are written to a temporary file which is then renamed over
+// `path` so that a crash never leaves behind a partially written file. The
+// previous contents of `path` are kept in `path`.bak and get_pkgs() falls back
+// to that file if `path` can't be parsed
+fn write_file(path: &str, contents: &str) -> Result<(), ParserError> {
+    let tmp    = format!("{}.tmp.{}", path, std::process::id());
+    let backup = backup_path(path);
+    let err    = || ParserError::WriteError(path.to_string());
+
+    if let Ok(old) = fs::read(path) {
+        let tmp_backup = format!("{}.tmp.{}", backup, std::process::id());
+
+        if write_synced(&tmp_backup, &old).and_then(|_| fs::rename(&tmp_backup, &backup)).is_err() {
+            let _ = fs::remove_file(&tmp_backup);
+            return Err(err());
+        }
+    }
+
+    if write_synced(&tmp, contents.as_bytes()).and_then(|_| fs::rename(&tmp, path)).is_err() {
+        let _ = fs::remove_file(&tmp);
+        return Err(err());
+    }
 
-    if fs::remove_file(&path).is_err() {
-        return;
+    // make the rename itself durable
+    if let Some(dir) = Path::new(path).parent() {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
     }
 
+    Ok(())
+}
+
+pub fn save_pkgs(path: &str, pkgs: HashMap<String, PkgInfo>) -> Result<(), ParserError> {
+
     let mut conf = ConfigWriter {
         packages: Vec::new()
     };
@@ -135,127 +522,262 @@ pub fn save_pkgs(path: &str, pkgs: HashMap<String, PkgInfo>) {
         conf.packages.push(v);
     }
 
-    File::create(&path)
-    .unwrap()
-    .write_all(
-        toml::to_string(&conf)
-        .unwrap()
-        .as_bytes()
-    ).unwrap();
+    match toml::to_string(&conf) {
+        Ok(contents) => write_file(path, &contents),
+        Err(_)       => Err(ParserError::WriteError(path.to_string()))
+    }
 }
 
-pub fn get_pubkeys() -> Result<Vec<String>, ParserError> {
+pub fn manifest_path(name: &str) -> Result<String, ParserError> {
+    // package names end up in file names so they must not be able to escape the directory
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(ParserError::NotFoundError(name.to_string()));
+    }
 
-    let mut contents = String::new();
-    let mut res: Vec<String> = Vec::new();
+    Ok(expand(&format!("manifests/{}.toml", name)))
+}
 
-    let mut f = match File::open(expand("KEYRING.toml")) {
-        Ok(val)  => val,
-        Err(err) => match err.kind() {
-            ErrorKind::NotFound => return Err(ParserError::NotFoundError),
-            _                   => return Err(ParserError::GenericError),
-        }
+pub fn get_manifest(name: &str) -> Result<Manifest, ParserError> {
+    let path     = manifest_path(name)?;
+    let contents = read_file(&path)?;
+
+    parse_toml(&path, &contents)
+}
+
+pub fn get_manifests() -> Result<Vec<Manifest>, ParserError> {
+    let dir = expand("manifests");
+    let mut res: Vec<Manifest> = Vec::new();
+
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_)      => return Ok(res)
     };
 
-    match f.read_to_string(&mut contents) {
-        Ok(_)  => (),
-        Err(_) => return Err(ParserError::ReadError)
+    for entry in entries.flatten() {
+        let path = entry.path().display().to_string();
+
+        if path.ends_with(".toml") {
+            res.push(parse_toml(&path, &read_file(&path)?)?);
+        }
     }
 
-    let config: KeyringConfigInternal = toml::from_str(&contents).unwrap();
+    Ok(res)
+}
+
+pub fn save_manifest(manifest: &Manifest) -> Result<(), ParserError> {
+    let path = manifest_path(&manifest.name)?;
 
-    if !config.signers.is_some() {
-        return Err(ParserError::EmptyFileError);
+    if fs::create_dir_all(expand("manifests")).is_err() {
+        return Err(ParserError::WriteError(path));
     }
 
-    for val in config.signers.unwrap() {
-        res.push(val.key.unwrap());
+    match toml::to_string(manifest) {
+        Ok(contents) => write_file(&path, &contents),
+        Err(_)       => Err(ParserError::WriteError(path))
     }
+}
+
+pub fn remove_manifest(name: &str) -> Result<(), ParserError> {
+    let path = manifest_path(name)?;
 
-    return Ok(res)
+    for file in [backup_path(&path), path.clone()].iter() {
+        if Path::new(file).exists() && fs::remove_file(file).is_err() {
+            return Err(ParserError::WriteError(file.to_string()));
+        }
+    }
+
+    Ok(())
 }
 
-pub fn get_signers() -> Result<Vec<KeyringEntry>, ParserError> {
+pub fn get_generations() -> Result<Vec<Generation>, ParserError> {
+    let dir = expand("generations");
+    let mut res: Vec<Generation> = Vec::new();
 
-    let mut contents = String::new();
-    let mut res: Vec<KeyringEntry> = Vec::new();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_)      => return Ok(res)
+    };
 
-    let mut f = match File::open(expand("KEYRING.toml")) {
-        Ok(val)  => val,
-        Err(err) => match err.kind() {
-            ErrorKind::NotFound => return Err(ParserError::NotFoundError),
-            _                   => return Err(ParserError::GenericError),
+    for entry in entries.flatten() {
+        let path = entry.path().display().to_string();
+
+        if path.ends_with(".toml") {
+            res.push(parse_toml(&path, &read_file(&path)?)?);
         }
-    };
+    }
 
-    match f.read_to_string(&mut contents) {
-        Ok(_)  => (),
-        Err(_) => return Err(ParserError::ReadError)
+    res.sort_by_key(|generation| generation.number);
+    Ok(res)
+}
+
+pub fn save_generation(generation: &Generation) -> Result<(), ParserError> {
+    let path = expand(&format!("generations/{}.toml", generation.number));
+
+    if fs::create_dir_all(expand("generations")).is_err() {
+        return Err(ParserError::WriteError(path));
     }
 
-    let config: KeyringConfigInternal = toml::from_str(&contents).unwrap();
+    match toml::to_string(generation) {
+        Ok(contents) => write_file(&path, &contents),
+        Err(_)       => Err(ParserError::WriteError(path))
+    }
+}
 
-    if !config.signers.is_some() {
-        return Err(ParserError::EmptyFileError);
+// Contents of `path`, or None if the file doesn't exist
+pub fn get_contents(path: &str) -> Result<Option<String>, ParserError> {
+    match read_file(path) {
+        Ok(contents)                       => Ok(Some(contents)),
+        Err(ParserError::NotFoundError(_)) => Ok(None),
+        Err(err)                           => Err(err)
     }
+}
 
-    for val in config.signers.unwrap() {
-        res.push(KeyringEntry {
-            name:      val.name.unwrap().clone(),
-            email:     val.email.unwrap().clone(),
-            key:       val.key.unwrap().clone(),
-            signature: val.signature.unwrap().clone()
-        });
+// Replace the contents of `path` with `contents` or remove the file if `contents` is None
+pub fn restore_contents(path: &str, contents: &Option<String>) -> Result<(), ParserError> {
+    match contents {
+        Some(contents) => {
+            if let Some(dir) = Path::new(path).parent() {
+                if fs::create_dir_all(dir).is_err() {
+                    return Err(ParserError::WriteError(path.to_string()));
+                }
+            }
+
+            write_file(path, contents)
+        },
+        None => match fs::remove_file(path) {
+            Ok(_)                                         => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(_)                                        => Err(ParserError::WriteError(path.to_string()))
+        }
     }
+}
 
-    return Ok(res)
+pub fn get_journal() -> Result<Option<Journal>, ParserError> {
+    let path = expand("transaction.toml");
+
+    match get_contents(&path)? {
+        Some(contents) => Ok(Some(parse_toml(&path, &contents)?)),
+        None           => Ok(None)
+    }
 }
 
-fn update_keyring_internal(signers: Vec<KeyringEntry>) {
+pub fn save_journal(journal: &Journal) -> Result<(), ParserError> {
+    let path = expand("transaction.toml");
 
-    let path = expand("KEYRING.toml");
+    match toml::to_string(journal) {
+        Ok(contents) => write_file(&path, &contents),
+        Err(_)       => Err(ParserError::WriteError(path))
+    }
+}
 
-    if fs::remove_file(&path).is_err() {
-        return;
+// Remove the journal, which commits the transaction
+pub fn remove_journal() -> Result<(), ParserError> {
+    let path = expand("transaction.toml");
+
+    for file in [path.clone(), backup_path(&path)].iter() {
+        if Path::new(file).exists() && fs::remove_file(file).is_err() {
+            return Err(ParserError::WriteError(file.to_string()));
+        }
     }
 
-    let conf = KeyringConfig {
-        signers: signers
-    };
+    Ok(())
+}
 
-    File::create(&path)
-    .unwrap()
-    .write_all(
-        toml::to_string(&conf)
-        .unwrap()
-        .as_bytes()
-    ).unwrap();
+pub fn get_pubkeys() -> Result<Vec<String>, ParserError> {
+    Ok(get_signers()?.into_iter().map(|signer| signer.key).collect())
 }
 
-pub fn save_keyring(signers: Vec<KeyringEntry>) {
-    update_keyring_internal(signers);
+pub fn get_signers() -> Result<Vec<KeyringEntry>, ParserError> {
+    Ok(get_keyring()?.signers)
 }
 
-pub fn save_keyring_default() {
+pub fn get_keyring() -> Result<KeyringConfig, ParserError> {
+
+    let path     = expand("KEYRING.toml");
+    let contents = read_file(&path)?;
+    let mut res: Vec<KeyringEntry> = Vec::new();
 
-    let mut vec: Vec<KeyringEntry> = Vec::new();
-    let init_entry = KeyringEntry {
-        name:      String::from("rficu"),
-        email:     String::from("rficu@email.com"),
-        key:       String::from("3c2PgNisX4vOumXAYVETS1aDKLHYEuhKSo7i1xnwr2Y="),
-        signature: String::from("+Bl5DtPMfKsxKd4eNQybgpbcrF70TuyMfp3Eyu8xQ1CWkBrhDEcx0jUO084EMZ7dbVw/v+0x0MMkbX/gZlGvBQ==")
+    let config: KeyringConfigInternal = parse_toml(&path, &contents)?;
 
+    let signers = match config.signers {
+        Some(signers) => signers,
+        None          => return Err(ParserError::EmptyFileError(path))
     };
 
-    vec.push(init_entry);
-    update_keyring_internal(vec);
+    for (i, val) in signers.into_iter().enumerate() {
+        let line = entry_line(&contents, "signers", i);
+
+        res.push(KeyringEntry {
+            name:           required(val.name, &path, line, "name")?,
+            email:          required(val.email, &path, line, "email")?,
+            key:            required(val.key, &path, line, "key")?,
+            signature:      val.signature.unwrap_or_default(),
+            valid_from:     val.valid_from.unwrap_or_default(),
+            valid_until:    val.valid_until.unwrap_or_default(),
+            certifications: val.certifications.unwrap_or_default(),
+            rotation:       val.rotation
+        });
+    }
+
+    Ok(KeyringConfig {
+        serial:      config.serial.unwrap_or_default(),
+        expires:     config.expires.unwrap_or_default(),
+        signer:      config.signer.unwrap_or_default(),
+        signature:   config.signature.unwrap_or_default(),
+        signers:     res,
+        revocations: config.revocations.unwrap_or_default(),
+        owners:      config.owners.unwrap_or_default()
+    })
+}
+
+pub fn save_keyring(keyring: &KeyringConfig) -> Result<(), ParserError> {
+
+    let path = expand("KEYRING.toml");
+
+    match toml::to_string(keyring) {
+        Ok(contents) => write_file(&path, &contents),
+        Err(_)       => Err(ParserError::WriteError(path))
+    }
 }
 
-pub fn get_file_contents(path: &str) -> Vec<u8> {
-    let mut f = File::open(&path).expect("File not found");
-    let metadata = fs::metadata(&path).expect("Failed to read file size");
-    let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("buffer overflow");
+// Add `anchors` to the trust anchors of ~/.config/pkgman/pkgman.toml
+//
+// The other settings of the file are kept as they are
+pub fn save_trust_anchors(anchors: &[settings::TrustAnchor]) -> Result<(), ParserError> {
+    let path = expand("pkgman.toml");
+
+    let mut conf: toml::value::Table = match read_file(&path) {
+        Ok(contents)                       => parse_toml(&path, &contents)?,
+        Err(ParserError::NotFoundError(_)) => toml::value::Table::new(),
+        Err(err)                           => return Err(err)
+    };
+
+    let mut list = match conf.remove("trust_anchors") {
+        Some(toml::Value::Array(list)) => list,
+        _                              => Vec::new()
+    };
+
+    for anchor in anchors {
+        match toml::Value::try_from(anchor) {
+            Ok(val) => list.push(val),
+            Err(_)  => return Err(ParserError::WriteError(path))
+        }
+    }
+
+    conf.insert(String::from("trust_anchors"), toml::Value::Array(list));
 
-    return buffer;
+    match toml::to_string(&toml::Value::Table(conf)) {
+        Ok(contents) => write_file(&path, &contents),
+        Err(_)       => Err(ParserError::WriteError(path))
+    }
+}
+
+pub fn get_file_contents(path: &str) -> Result<Vec<u8>, ParserError> {
+    match fs::read(path) {
+        Ok(buffer) => Ok(buffer),
+        Err(err)   => match err.kind() {
+            ErrorKind::NotFound => Err(ParserError::NotFoundError(path.to_string())),
+            _                   => Err(ParserError::ReadError(path.to_string()))
+        }
+    }
 }
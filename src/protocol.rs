@@ -0,0 +1,89 @@
+extern crate ring;
+extern crate toml;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Serialize, Deserialize};
+
+use crate::parser;
+
+// Version of the pubsub protocol, bumped on every incompatible change
+// to the envelope or to the payloads it carries
+pub static PROTOCOL_VERSION: u32 = 1;
+
+// Every message sent on the PST_* topics is wrapped in an Envelope
+//
+// The request id is chosen randomly by the requester and echoed back
+// by the daemons in their answers so that clients running concurrent queries
+// can tell their own answers apart from answers meant for someone else
+//
+// Payload depends on the topic:
+//  - PST_KEYRING_QUERY: unused
+//  - PST_KEYRING:       TOML-serialized parser::KeyringConfig, signed by a trust anchor
+//  - PST_BATCH_QUERY:   TOML-serialized BatchQuery
+//  - PST_BATCH:         TOML-serialized BatchAnswer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version:    u32,
+    pub request_id: String,
+    pub requester:  String,
+    pub payload:    String
+}
+
+// Query for several packages at once
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchQuery {
+    pub names: Vec<String>
+}
+
+// Answer to a BatchQuery, contains the packages the daemon knows of
+// (or a package that provides the name, see daemon.rs) and omits the rest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchAnswer {
+    #[serde(default)]
+    pub packages: Vec<parser::PkgInfo>
+}
+
+pub fn new_request_id() -> String {
+    let mut buf = [0u8; 16];
+
+    SystemRandom::new().fill(&mut buf).unwrap();
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// create a new request with a fresh request id
+pub fn request(requester: &str, payload: &str) -> Envelope {
+    Envelope {
+        version:    PROTOCOL_VERSION,
+        request_id: new_request_id(),
+        requester:  requester.to_string(),
+        payload:    payload.to_string()
+    }
+}
+
+// create an answer to `req`
+pub fn response(req: &Envelope, payload: &str) -> Envelope {
+    Envelope {
+        version:    PROTOCOL_VERSION,
+        request_id: req.request_id.clone(),
+        requester:  req.requester.clone(),
+        payload:    payload.to_string()
+    }
+}
+
+pub fn encode(env: &Envelope) -> String {
+    toml::to_string(env).unwrap()
+}
+
+// Decode a message received from pubsub
+//
+// Returns None if the message is not a valid envelope or
+// if it was created using an unsupported protocol version
+pub fn decode(data: &[u8]) -> Option<Envelope> {
+    let env: Envelope = toml::from_str(std::str::from_utf8(data).ok()?).ok()?;
+
+    if env.version != PROTOCOL_VERSION {
+        return None;
+    }
+
+    Some(env)
+}
@@ -0,0 +1,565 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Error;
+use crate::ipfs;
+use crate::network;
+use crate::parser;
+use crate::transport;
+
+#[derive(Debug)]
+pub enum ResolveError {
+    InvalidConstraint(String),
+
+    // no package available on the network satisfies `dependency` of `package`
+    Unsatisfiable {
+        package:    String,
+        dependency: String
+    },
+
+    Conflict {
+        package: String,
+        other:   String
+    },
+
+    Cycle(String)
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::InvalidConstraint(val) => write!(f, "invalid package relation \"{}\"", val),
+            ResolveError::Unsatisfiable { package, dependency } => {
+                write!(f, "{}: dependency \"{}\" can't be satisfied", package, dependency)
+            },
+            ResolveError::Conflict { package, other } => write!(f, "{} conflicts with {}", package, other),
+            ResolveError::Cycle(name) => write!(f, "dependency cycle involving {}", name)
+        }
+    }
+}
+
+impl From<ResolveError> for Error {
+    fn from(err: ResolveError) -> Self {
+        Error::Resolve(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Any,
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+
+// Package relation such as "glibc >= 2.31"
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    pub name:    String,
+    pub op:      Op,
+    pub version: String
+}
+
+impl Constraint {
+    pub fn matches(&self, version: &str) -> bool {
+        if self.op == Op::Any {
+            return true;
+        }
+
+        match network::compare_versions(version, &self.version) {
+            Some(Ordering::Less)    => self.op == Op::Lt || self.op == Op::Le,
+            Some(Ordering::Equal)   => self.op == Op::Eq || self.op == Op::Le || self.op == Op::Ge,
+            Some(Ordering::Greater) => self.op == Op::Gt || self.op == Op::Ge,
+            None                    => false
+        }
+    }
+}
+
+// Parse a package relation
+//
+// A relation is a package name optionally followed by one of the operators
+// =, <, <=, > or >= and a version, e.g., "zlib", "glibc >= 2.31" or "llvm=11.1.0"
+pub fn parse_constraint(val: &str) -> Result<Constraint, ResolveError> {
+    let invalid = || ResolveError::InvalidConstraint(val.to_string());
+    let valid   = |s: &str| !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || c == ',');
+
+    let (name, op, version) = match val.find(['<', '>', '=']) {
+        Some(idx) => {
+            let rest = &val[idx..];
+
+            let (op, len) = if rest.starts_with(">=") {
+                (Op::Ge, 2)
+            } else if rest.starts_with("<=") {
+                (Op::Le, 2)
+            } else if rest.starts_with("==") {
+                (Op::Eq, 2)
+            } else if rest.starts_with('>') {
+                (Op::Gt, 1)
+            } else if rest.starts_with('<') {
+                (Op::Lt, 1)
+            } else {
+                (Op::Eq, 1)
+            };
+
+            (val[..idx].trim(), op, rest[len..].trim())
+        },
+        None => (val.trim(), Op::Any, "")
+    };
+
+    if !valid(name) || (op != Op::Any && !valid(version)) {
+        return Err(invalid());
+    }
+
+    Ok(Constraint {
+        name:    name.to_string(),
+        op,
+        version: version.to_string()
+    })
+}
+
+// Check that every relation of `pkg` can be parsed
+//
+// Entries of `provides` name a virtual package and they may only have an exact version
+pub fn check_relations(pkg: &parser::PkgInfo) -> Result<(), ResolveError> {
+    for val in pkg.depends.iter().chain(pkg.conflicts.iter()).chain(pkg.replaces.iter()) {
+        parse_constraint(val)?;
+    }
+
+    for val in pkg.provides.iter() {
+        let provide = parse_constraint(val)?;
+
+        if provide.op != Op::Any && provide.op != Op::Eq {
+            return Err(ResolveError::InvalidConstraint(val.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// Does `pkg` satisfy `constraint`, either by itself or by one of its provides
+//
+// A provide without a version satisfies only relations without a version
+pub fn satisfies(pkg: &parser::PkgInfo, constraint: &Constraint) -> bool {
+    if pkg.name == constraint.name && constraint.matches(&pkg.version) {
+        return true;
+    }
+
+    pkg.provides
+        .iter()
+        .filter_map(|val| parse_constraint(val).ok())
+        .any(|provide| {
+            provide.name == constraint.name && match provide.op {
+                Op::Any => constraint.op == Op::Any,
+                _       => constraint.matches(&provide.version)
+            }
+        })
+}
+
+// Does `pkg` provide the (virtual) package `name`
+pub fn provides(pkg: &parser::PkgInfo, name: &str) -> bool {
+    pkg.provides
+        .iter()
+        .filter_map(|val| parse_constraint(val).ok())
+        .any(|provide| provide.name == name)
+}
+
+// Result of resolve()
+#[derive(Debug)]
+pub struct Plan {
+    // packages to install, each package comes after the packages it depends on
+    pub install: Vec<parser::PkgInfo>,
+
+    // installed packages that are replaced by the packages of `install`
+    pub replace: Vec<String>
+}
+
+fn relations(values: &[String]) -> Result<Vec<Constraint>, ResolveError> {
+    values.iter().map(|val| parse_constraint(val)).collect()
+}
+
+// Visit `name` and its dependencies in depth-first order and append them to `order`
+fn visit(
+    name: &str,
+    set: &HashMap<String, parser::PkgInfo>,
+    state: &mut HashMap<String, bool>,
+    order: &mut Vec<parser::PkgInfo>
+) -> Result<(), ResolveError> {
+    match state.get(name) {
+        Some(true)  => return Ok(()),
+        Some(false) => return Err(ResolveError::Cycle(name.to_string())),
+        None        => { }
+    }
+
+    state.insert(name.to_string(), false);
+
+    let pkg = &set[name];
+
+    for dep in relations(&pkg.depends)? {
+        let mut providers: Vec<&String> = set
+            .iter()
+            .filter(|(other, info)| *other != name && satisfies(info, &dep))
+            .map(|(other, _)| other)
+            .collect();
+
+        providers.sort();
+
+        for other in providers {
+            visit(other, set, state, order)?;
+        }
+    }
+
+    state.insert(name.to_string(), true);
+    order.push(pkg.clone());
+
+    Ok(())
+}
+
+// Resolve the packages needed to install `names` on top of `installed`
+//
+// The packages are queried from the network together with the transitive
+// closure of their dependencies. Dependencies already satisfied by an installed
+// package are not queried. The resulting set is refused if a dependency can't
+// be satisfied, if two packages conflict with each other or if the dependencies
+// form a cycle
+//
+// If `skip_missing` is set, packages of `names` that are not found on the
+// network are left out instead of failing
+pub async fn resolve(
+    transport: &dyn transport::Transport,
+    names: &[String],
+    installed: &HashMap<String, parser::PkgInfo>,
+    skip_missing: bool
+) -> Result<Plan, Error> {
+
+    let mut set: HashMap<String, parser::PkgInfo> = HashMap::new();
+    let mut wanted_by: HashMap<String, (String, String)> = HashMap::new();
+    let mut queried: Vec<String> = names.to_vec();
+    let mut pending: Vec<String> = names.to_vec();
+
+    // query the dependencies of each level of the dependency tree with one batch query
+    while !pending.is_empty() {
+        let mut answers = network::query_many(transport, &pending).await?;
+        let mut added: Vec<String> = Vec::new();
+        let mut next: Vec<String> = Vec::new();
+
+        for name in pending.iter() {
+            let pkg = match (answers.remove(name), wanted_by.get(name)) {
+                (Some(pkg), _) => pkg,
+                (None, Some((package, dependency))) => {
+                    return Err(Error::Resolve(ResolveError::Unsatisfiable {
+                        package:    package.clone(),
+                        dependency: dependency.clone()
+                    }));
+                },
+                (None, None) if skip_missing => {
+                    println!("Package {} not found on the network, skipping it", name);
+                    continue;
+                },
+                (None, None) => return Err(Error::IPFS(ipfs::IPFSError::NotFound))
+            };
+
+            check_relations(&pkg)?;
+
+            // a virtual package may be provided by a package that is already in the set
+            if !set.contains_key(&pkg.name) {
+                added.push(pkg.name.clone());
+                set.insert(pkg.name.clone(), pkg);
+            }
+        }
+
+        for name in added.iter() {
+            for (dep, val) in relations(&set[name].depends)?.into_iter().zip(set[name].depends.iter()) {
+                let satisfied = set.values().any(|pkg| satisfies(pkg, &dep))
+                    || installed.values().any(|pkg| satisfies(pkg, &dep));
+
+                if satisfied || next.contains(&dep.name) {
+                    continue;
+                }
+
+                // the newest version on the network doesn't satisfy the dependency
+                if queried.contains(&dep.name) {
+                    return Err(Error::Resolve(ResolveError::Unsatisfiable {
+                        package:    name.clone(),
+                        dependency: val.clone()
+                    }));
+                }
+
+                wanted_by.insert(dep.name.clone(), (name.clone(), val.clone()));
+                queried.push(dep.name.clone());
+                next.push(dep.name.clone());
+            }
+        }
+
+        pending = next;
+    }
+
+    let mut replace: Vec<String> = Vec::new();
+
+    for pkg in set.values() {
+        for constraint in relations(&pkg.replaces)? {
+            for other in installed.values() {
+                if other.name != pkg.name
+                    && !set.contains_key(&other.name)
+                    && !replace.contains(&other.name)
+                    && satisfies(other, &constraint)
+                {
+                    replace.push(other.name.clone());
+                }
+            }
+        }
+    }
+
+    // the state of the system after the installation
+    let kept: Vec<&parser::PkgInfo> = installed
+        .values()
+        .filter(|pkg| !set.contains_key(&pkg.name) && !replace.contains(&pkg.name))
+        .collect();
+
+    let result: Vec<&parser::PkgInfo> = set.values().chain(kept.iter().cloned()).collect();
+
+    for pkg in result.iter() {
+        let changed = set.contains_key(&pkg.name);
+
+        // dependencies of installed packages may be broken by an upgrade
+        // or by a replaced package, but only report the ones this installation breaks
+        for (dep, val) in relations(&pkg.depends)?.into_iter().zip(pkg.depends.iter()) {
+            let before = installed.values().any(|other| satisfies(other, &dep));
+            let after  = result.iter().any(|other| satisfies(other, &dep));
+
+            if !after && (changed || before) {
+                return Err(Error::Resolve(ResolveError::Unsatisfiable {
+                    package:    pkg.name.clone(),
+                    dependency: val.clone()
+                }));
+            }
+        }
+
+        for constraint in relations(&pkg.conflicts)? {
+            for other in result.iter() {
+                let involved = changed || set.contains_key(&other.name);
+
+                if involved && other.name != pkg.name && satisfies(other, &constraint) {
+                    return Err(Error::Resolve(ResolveError::Conflict {
+                        package: pkg.name.clone(),
+                        other:   other.name.clone()
+                    }));
+                }
+            }
+        }
+    }
+
+    let mut state: HashMap<String, bool> = HashMap::new();
+    let mut order: Vec<parser::PkgInfo> = Vec::new();
+    let mut roots: Vec<&String> = set.keys().collect();
+
+    roots.sort();
+
+    for name in roots {
+        visit(name, &set, &mut state, &mut order)?;
+    }
+
+    Ok(Plan {
+        install: order,
+        replace
+    })
+}
+
+// Installed packages that would be left with an unsatisfied dependency if
+// `names` were removed, including the packages that depend on those in turn
+//
+// Each package of the result comes before the packages it depends on, so
+// the packages can be removed in the returned order
+pub fn dependents(names: &[String], installed: &HashMap<String, parser::PkgInfo>) -> Vec<String> {
+    let mut removed: Vec<String> = names.to_vec();
+    let mut res: Vec<String> = Vec::new();
+
+    loop {
+        let mut broken: Vec<String> = installed
+            .values()
+            .filter(|pkg| !removed.contains(&pkg.name))
+            .filter(|pkg| {
+                pkg.depends
+                    .iter()
+                    .filter_map(|val| parse_constraint(val).ok())
+                    .any(|dep| {
+                        let (gone, left): (Vec<&parser::PkgInfo>, Vec<&parser::PkgInfo>) = installed
+                            .values()
+                            .filter(|other| satisfies(other, &dep))
+                            .partition(|other| removed.contains(&other.name));
+
+                        !gone.is_empty() && left.is_empty()
+                    })
+            })
+            .map(|pkg| pkg.name.clone())
+            .collect();
+
+        if broken.is_empty() {
+            break;
+        }
+
+        broken.sort();
+        removed.extend(broken.iter().cloned());
+        res.extend(broken);
+    }
+
+    res.reverse();
+    res
+}
+
+// Installed packages that are not needed by any package of `explicit`,
+// either directly or through other dependencies
+pub fn orphans(installed: &HashMap<String, parser::PkgInfo>, explicit: &[String]) -> Vec<String> {
+    let mut needed: Vec<String> = explicit.iter().filter(|name| installed.contains_key(*name)).cloned().collect();
+    let mut i = 0;
+
+    while i < needed.len() {
+        let pkg = &installed[&needed[i]];
+
+        for dep in pkg.depends.iter().filter_map(|val| parse_constraint(val).ok()) {
+            for other in installed.values() {
+                if satisfies(other, &dep) && !needed.contains(&other.name) {
+                    needed.push(other.name.clone());
+                }
+            }
+        }
+
+        i += 1;
+    }
+
+    let mut res: Vec<String> = installed
+        .keys()
+        .filter(|name| !needed.contains(name))
+        .cloned()
+        .collect();
+
+    res.sort();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn pkg(name: &str, version: &str, depends: &[&str], provides: &[&str]) -> parser::PkgInfo {
+        let mut pkg = testing::pkginfo(name, version);
+
+        pkg.depends  = depends.iter().map(|val| val.to_string()).collect();
+        pkg.provides = provides.iter().map(|val| val.to_string()).collect();
+        pkg
+    }
+
+    fn set(pkgs: Vec<parser::PkgInfo>) -> HashMap<String, parser::PkgInfo> {
+        pkgs.into_iter().map(|pkg| (pkg.name.clone(), pkg)).collect()
+    }
+
+    fn names(pkgs: &[parser::PkgInfo]) -> Vec<&str> {
+        pkgs.iter().map(|pkg| pkg.name.as_str()).collect()
+    }
+
+    #[test]
+    fn constraints() {
+        let dep = parse_constraint("glibc >= 2.31").unwrap();
+        assert_eq!((dep.name.as_str(), dep.op, dep.version.as_str()), ("glibc", Op::Ge, "2.31"));
+
+        let dep = parse_constraint("llvm=11.1.0").unwrap();
+        assert_eq!((dep.name.as_str(), dep.op, dep.version.as_str()), ("llvm", Op::Eq, "11.1.0"));
+
+        let dep = parse_constraint("zlib").unwrap();
+        assert_eq!((dep.name.as_str(), dep.op), ("zlib", Op::Any));
+
+        assert!(dep.matches("1.2.11"));
+        assert!(parse_constraint("glibc < 2.31").unwrap().matches("2.30"));
+        assert!(!parse_constraint("glibc < 2.31").unwrap().matches("2.31"));
+        assert!(parse_constraint("glibc <= 2.31").unwrap().matches("2.31"));
+        assert!(!parse_constraint("glibc > 2.31").unwrap().matches("2.31"));
+    }
+
+    #[test]
+    fn invalid_constraints() {
+        for val in ["", ">= 2.31", "glibc >=", "gl ibc", "glibc >= 2, 3"].iter() {
+            assert!(matches!(parse_constraint(val), Err(ResolveError::InvalidConstraint(_))), "{}", val);
+        }
+
+        assert!(check_relations(&pkg("hello", "1.0", &["glibc >="], &[])).is_err());
+        assert!(check_relations(&pkg("hello", "1.0", &[], &["cc >= 11"])).is_err());
+        assert!(check_relations(&pkg("hello", "1.0", &["glibc >= 2.31"], &["cc = 11"])).is_ok());
+    }
+
+    #[test]
+    fn provides_satisfy_relations() {
+        let clang = pkg("clang", "11.1.0", &[], &["cc = 11", "c-compiler"]);
+
+        assert!(satisfies(&clang, &parse_constraint("clang >= 11").unwrap()));
+        assert!(satisfies(&clang, &parse_constraint("cc >= 10").unwrap()));
+        assert!(!satisfies(&clang, &parse_constraint("cc > 11").unwrap()));
+        assert!(satisfies(&clang, &parse_constraint("c-compiler").unwrap()));
+        assert!(!satisfies(&clang, &parse_constraint("c-compiler >= 1").unwrap()));
+        assert!(provides(&clang, "cc"));
+        assert!(!provides(&clang, "gcc"));
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let pkgs = set(vec![
+            pkg("app", "1.0", &["libfoo >= 1", "cc"], &[]),
+            pkg("libfoo", "1.0", &["libc"], &[]),
+            pkg("libc", "2.31", &[], &[]),
+            pkg("clang", "11", &[], &["cc"])
+        ]);
+
+        let mut state = HashMap::new();
+        let mut order = Vec::new();
+
+        visit("app", &pkgs, &mut state, &mut order).unwrap();
+        assert_eq!(names(&order), vec!["libc", "libfoo", "clang", "app"]);
+    }
+
+    #[test]
+    fn cycle_is_rejected() {
+        let pkgs = set(vec![
+            pkg("a", "1.0", &["b"], &[]),
+            pkg("b", "1.0", &["c"], &[]),
+            pkg("c", "1.0", &["a"], &[])
+        ]);
+
+        let mut state = HashMap::new();
+        let mut order = Vec::new();
+
+        assert!(matches!(visit("a", &pkgs, &mut state, &mut order), Err(ResolveError::Cycle(_))));
+    }
+
+    #[test]
+    fn dependents_of_removed_packages() {
+        let installed = set(vec![
+            pkg("app", "1.0", &["libfoo"], &[]),
+            pkg("tool", "1.0", &["cc"], &[]),
+            pkg("libfoo", "1.0", &["libc"], &[]),
+            pkg("libc", "2.31", &[], &[]),
+            pkg("gcc", "10", &[], &["cc"]),
+            pkg("clang", "11", &[], &["cc"])
+        ]);
+
+        assert_eq!(dependents(&["libc".to_string()], &installed), vec!["app", "libfoo"]);
+
+        // another provider of cc is left
+        assert!(dependents(&["gcc".to_string()], &installed).is_empty());
+        assert_eq!(dependents(&["gcc".to_string(), "clang".to_string()], &installed), vec!["tool"]);
+    }
+
+    #[test]
+    fn orphans_are_not_needed_by_explicit_packages() {
+        let installed = set(vec![
+            pkg("app", "1.0", &["libfoo"], &[]),
+            pkg("libfoo", "1.0", &["libc"], &[]),
+            pkg("libc", "2.31", &[], &[]),
+            pkg("libbar", "1.0", &[], &[])
+        ]);
+
+        assert_eq!(orphans(&installed, &["app".to_string()]), vec!["libbar"]);
+        assert_eq!(orphans(&installed, &[]), vec!["app", "libbar", "libc", "libfoo"]);
+    }
+}
@@ -0,0 +1,120 @@
+extern crate config;
+
+use serde::{Serialize, Deserialize};
+
+use crate::parser;
+
+// Key that is trusted without a signature from any other key
+//
+// Maintainer keys of the keyring are accepted only if they are signed by a
+// trust anchor (see trust.rs), and packages may be signed by the anchors too
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrustAnchor {
+    #[serde(default)]
+    pub name: String,
+
+    // base64-encoded Ed25519 public key
+    pub key:  String
+}
+
+// Number of signatures required for the packages matching `packages`, each
+// entry is a package name or a prefix of package names followed by `*`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SignaturePolicy {
+    pub packages:       Vec<String>,
+    pub min_signatures: usize
+}
+
+// Runtime settings of pkgman, read from ~/.config/pkgman/pkgman.toml
+//
+// The file is optional and every key has a default value so that an
+// installation created with `pkgman --init` works without one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // accept package entries where only the sha256 digest is signed instead
+    // of the canonical package metadata. This is only meant to be used while
+    // migrating old package lists and should be turned off afterwards
+    pub allow_legacy_signatures: bool,
+
+    // directory of a local content store (see store::DirStore) that is used
+    // to store and fetch packages instead of the IPFS node
+    pub content_store: Option<String>,
+
+    // Unix socket of a broker started with `pkgman --broker`. If set, pubsub
+    // messages are exchanged through the broker instead of the IPFS node
+    pub transport_socket: Option<String>,
+
+    // directory the packages are installed to, "~/" is expanded to the
+    // home directory of the user
+    pub prefix: String,
+
+    // number of packages that are downloaded at the same time
+    pub parallel_downloads: usize,
+
+    // maximum size of the download cache (see cache.rs) in MiB
+    pub cache_size: u64,
+
+    // roots of trust, set with `pkgman --init --root-key`
+    pub trust_anchors: Vec<TrustAnchor>,
+
+    // maximum number of certifications between a trust anchor and a trusted
+    // maintainer key, and the number of independent such paths required
+    pub trust_max_depth: usize,
+    pub trust_min_paths: usize,
+
+    // number of distinct authorized keys that must sign a package, and the
+    // policies of packages that need a different number (see trust::Web)
+    pub min_signatures:     usize,
+    pub signature_policies: Vec<SignaturePolicy>
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            allow_legacy_signatures: false,
+            content_store:           None,
+            transport_socket:        None,
+            prefix:                  String::from("~/.local"),
+            parallel_downloads:      4,
+            cache_size:              1024,
+            trust_anchors:           Vec::new(),
+            trust_max_depth:         3,
+            trust_min_paths:         1,
+            min_signatures:          1,
+            signature_policies:      Vec::new()
+        }
+    }
+}
+
+// expand leading "~/" of `path` to the home directory
+pub fn expand_home(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => parser::home().join(rest).display().to_string(),
+        None       => path.to_string()
+    }
+}
+
+pub fn get_settings() -> Result<Settings, parser::ParserError> {
+    let path     = parser::expand("pkgman.toml");
+    let mut conf = config::Config::new();
+
+    if let Err(err) = conf.merge(config::File::new(&path, config::FileFormat::Toml).required(false)) {
+        return Err(parser::ParserError::SyntaxError {
+            path,
+            line:    0,
+            column:  0,
+            message: err.to_string()
+        });
+    }
+
+    match conf.try_into() {
+        Ok(settings) => Ok(settings),
+        Err(err)     => Err(parser::ParserError::SyntaxError {
+            path,
+            line:    0,
+            column:  0,
+            message: err.to_string()
+        })
+    }
+}
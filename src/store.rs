@@ -0,0 +1,216 @@
+use async_trait::async_trait;
+use futures::{stream, Stream, TryStreamExt};
+use ipfs_api::IpfsClient;
+use sha2::{Sha256, Digest};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use crate::ipfs::IPFSError;
+use crate::settings;
+
+// size of the chunks DirStore reads and writes at a time
+static CHUNK_SIZE: usize = 64 * 1024;
+
+// Contents of a store entry, received in chunks
+pub type Chunks<'a> = Pin<Box<dyn Stream<Item = Result<Vec<u8>, IPFSError>> + 'a>>;
+
+// Storage for package contents
+//
+// Contents are addressed by an identifier returned by add(), for IPFS this is the CID
+// of the content. pkgman only ever treats the identifier as an opaque string which is
+// signed as part of parser::PkgInfo so any content-addressed storage can be used
+#[async_trait(?Send)]
+pub trait ContentStore {
+    // store the contents of file `path` and return its content identifier
+    async fn add(&self, path: &Path) -> Result<String, IPFSError>;
+
+    // fetch the content identified by `cid`
+    //
+    // The content is streamed so that the caller can verify it and
+    // stop reading without holding the whole content in memory
+    fn get<'a>(&'a self, cid: &'a str) -> Chunks<'a>;
+
+    // keep `cid` stored locally so that it can be served to others
+    async fn pin(&self, cid: &str) -> Result<(), IPFSError>;
+
+    // check whether `cid` is available
+    async fn has(&self, cid: &str) -> Result<bool, IPFSError>;
+}
+
+// Content store backed by the IPFS node of the local machine
+pub struct IpfsStore {
+    client: IpfsClient
+}
+
+impl IpfsStore {
+    pub fn new() -> IpfsStore {
+        IpfsStore {
+            client: IpfsClient::default()
+        }
+    }
+}
+
+impl Default for IpfsStore {
+    fn default() -> Self {
+        IpfsStore::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl ContentStore for IpfsStore {
+    async fn add(&self, path: &Path) -> Result<String, IPFSError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_)   => return Err(IPFSError::NotFound)
+        };
+
+        match self.client.add(file).await {
+            Ok(file) => Ok(file.hash),
+            Err(err) => {
+                println!("Failed to add file: {:#?}", err);
+                Err(IPFSError::Unknown)
+            }
+        }
+    }
+
+    fn get<'a>(&'a self, cid: &'a str) -> Chunks<'a> {
+        Box::pin(self.client
+            .cat(cid)
+            .map_ok(|chunk| chunk.to_vec())
+            .map_err(|err| {
+                println!("error getting file: {}", err);
+                IPFSError::Unknown
+            }))
+    }
+
+    async fn pin(&self, cid: &str) -> Result<(), IPFSError> {
+        match self.client.pin_add(cid, true).await {
+            Ok(_)  => Ok(()),
+            Err(err) => {
+                println!("Failed to pin {}: {}", cid, err);
+                Err(IPFSError::Unknown)
+            }
+        }
+    }
+
+    async fn has(&self, cid: &str) -> Result<bool, IPFSError> {
+        match self.client.block_stat(cid).await {
+            Ok(_)  => Ok(true),
+            Err(_) => Ok(false)
+        }
+    }
+}
+
+// Content store backed by a local directory
+//
+// Contents are stored in <root>/<sha256 of the content>, and the hex-encoded
+// digest prefixed with "sha256-" works as the content identifier. This makes it
+// possible to run the whole publish/download flow without an IPFS node
+pub struct DirStore {
+    root: PathBuf
+}
+
+impl DirStore {
+    pub fn new(root: &str) -> DirStore {
+        DirStore {
+            root: PathBuf::from(root)
+        }
+    }
+
+    fn path(&self, cid: &str) -> Result<PathBuf, IPFSError> {
+        let digest = match cid.strip_prefix("sha256-") {
+            Some(digest) => digest,
+            None         => return Err(IPFSError::NotFound)
+        };
+
+        if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(IPFSError::NotFound);
+        }
+
+        Ok(self.root.join(digest))
+    }
+}
+
+#[async_trait(?Send)]
+impl ContentStore for DirStore {
+    async fn add(&self, path: &Path) -> Result<String, IPFSError> {
+        let mut input = match File::open(path) {
+            Ok(input) => input,
+            Err(_)    => return Err(IPFSError::NotFound)
+        };
+
+        if fs::create_dir_all(&self.root).is_err() {
+            return Err(IPFSError::Unknown);
+        }
+
+        // copy the contents next to their final location while they're hashed,
+        // the digest is known only once everything has been read
+        let temp = self.root.join(format!(".add-{}", std::process::id()));
+        let mut sha256 = Sha256::new();
+        let mut buf    = vec![0u8; CHUNK_SIZE];
+
+        let res = File::create(&temp).and_then(|mut output| loop {
+            let n = input.read(&mut buf)?;
+
+            if n == 0 {
+                break output.sync_all();
+            }
+
+            sha256.update(&buf[..n]);
+            output.write_all(&buf[..n])?;
+        });
+
+        let cid = format!("sha256-{:x}", sha256.finalize());
+
+        match res.and_then(|_| fs::rename(&temp, self.path(&cid).unwrap())) {
+            Ok(_)  => Ok(cid),
+            Err(_) => {
+                let _ = fs::remove_file(&temp);
+                Err(IPFSError::Unknown)
+            }
+        }
+    }
+
+    fn get<'a>(&'a self, cid: &'a str) -> Chunks<'a> {
+        let file = match self.path(cid).and_then(|path| File::open(path).map_err(|_| IPFSError::NotFound)) {
+            Ok(file) => file,
+            Err(err) => return Box::pin(stream::once(async { Err(err) }))
+        };
+
+        Box::pin(stream::try_unfold(file, |mut file| async move {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+
+            match file.read(&mut buf) {
+                Ok(0)  => Ok(None),
+                Ok(n)  => {
+                    buf.truncate(n);
+                    Ok(Some((buf, file)))
+                },
+                Err(_) => Err(IPFSError::Unknown)
+            }
+        }))
+    }
+
+    async fn pin(&self, cid: &str) -> Result<(), IPFSError> {
+        // contents of the directory are never removed so there's nothing to pin
+        match self.has(cid).await? {
+            true  => Ok(()),
+            false => Err(IPFSError::NotFound)
+        }
+    }
+
+    async fn has(&self, cid: &str) -> Result<bool, IPFSError> {
+        Ok(self.path(cid)?.exists())
+    }
+}
+
+// Create the content store configured in pkgman.toml
+pub fn from_settings(settings: &settings::Settings) -> Box<dyn ContentStore> {
+    match &settings.content_store {
+        Some(dir) => Box::new(DirStore::new(dir)),
+        None      => Box::new(IpfsStore::new())
+    }
+}
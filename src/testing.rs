@@ -0,0 +1,141 @@
+// Helpers shared by the unit tests
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::archive;
+use crate::crypto;
+use crate::parser;
+use crate::settings;
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Create a new empty directory under the system temporary directory
+pub fn temp_dir() -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "pkgman-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// New Ed25519 key pair and its base64-encoded public key
+pub fn keypair() -> (Ed25519KeyPair, String) {
+    let pkcs8   = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    let keypair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let key     = base64::encode(keypair.public_key().as_ref());
+
+    (keypair, key)
+}
+
+// Unsigned package list entry of package `name` version `version`
+pub fn pkginfo(name: &str, version: &str) -> parser::PkgInfo {
+    parser::PkgInfo {
+        name:         name.to_string(),
+        version:      version.to_string(),
+        sha256:       String::new(),
+        ipfs:         String::new(),
+        signature:    String::new(),
+        signer:       String::new(),
+        timestamp:    0,
+        size:         0,
+        depends:      Vec::new(),
+        conflicts:    Vec::new(),
+        provides:     Vec::new(),
+        replaces:     Vec::new(),
+        cosignatures: Vec::new()
+    }
+}
+
+// Home directory of the current thread, see home()
+pub struct Home(PathBuf);
+
+impl Deref for Home {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Home {
+    fn drop(&mut self) {
+        parser::set_home(None);
+    }
+}
+
+// Use a new directory with an empty package list as the home directory of
+// the current thread, until the returned Home is dropped
+pub fn home() -> Home {
+    let home = temp_dir();
+
+    fs::create_dir_all(home.join(".config/pkgman")).unwrap();
+    fs::write(home.join(".config/pkgman/PKGLIST.toml"), "").unwrap();
+    parser::set_home(Some(home.clone()));
+
+    Home(home)
+}
+
+// Build the archive of package `name` version `version` containing `files`,
+// pairs of a path and the contents of the file, in directory `dir`
+pub fn package(dir: &Path, name: &str, version: &str, files: &[(&str, &str)]) -> archive::Package {
+    let staging = dir.join(format!("{}-{}", name, version));
+    let path    = dir.join(format!("{}-{}.pkg.tar.zst", name, version));
+
+    for (file, contents) in files {
+        let file = staging.join(file);
+
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, contents).unwrap();
+    }
+
+    let file = fs::File::create(&path).unwrap();
+    archive::write(staging.to_str().unwrap(), name, version, file).unwrap();
+    archive::open(&path, name, version).unwrap()
+}
+
+// Settings trusting the keys of `anchors`
+pub fn settings(anchors: &[&str]) -> settings::Settings {
+    settings::Settings {
+        trust_anchors: anchors.iter().map(|key| crate::trust::anchor(key).unwrap()).collect(),
+        ..settings::Settings::default()
+    }
+}
+
+// Keyring entry of maintainer `name` with key `key`, certified by `certifiers`
+pub fn entry(name: &str, key: &str, certifiers: &[&Ed25519KeyPair]) -> parser::KeyringEntry {
+    let mut entry = parser::KeyringEntry {
+        name:           name.to_string(),
+        email:          format!("{}@example.com", name),
+        key:            key.to_string(),
+        signature:      String::new(),
+        valid_from:     0,
+        valid_until:    0,
+        certifications: Vec::new(),
+        rotation:       None
+    };
+
+    for keypair in certifiers {
+        crypto::certify(keypair, &mut entry).unwrap();
+    }
+
+    entry
+}
+
+// Package list entry of package `name` version `version` signed by `keypair`
+pub fn signed(keypair: &Ed25519KeyPair, name: &str, version: &str) -> parser::PkgInfo {
+    let mut pkg = pkginfo(name, version);
+
+    pkg.sha256 = "0".repeat(64);
+    pkg.ipfs   = format!("sha256-{}", pkg.sha256);
+    crypto::sign_pkginfo(keypair, &mut pkg).unwrap();
+    pkg
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+use crate::install::InstallError;
+use crate::parser;
+
+// Set of file changes that are applied to the system all at once
+//
+// New files are first staged next to their final location with a ".pkgman-new"
+// suffix, which doesn't touch any installed file. Committing the transaction then
+//
+//   1. saves a journal (~/.config/pkgman/transaction.toml) containing the
+//      current PKGLIST.toml, the current manifests of the affected packages
+//      and the list of files that are about to be replaced or removed
+//   2. moves the replaced and removed files aside with a ".pkgman-old" suffix
+//      and moves the staged files into place
+//   3. saves the new manifests and PKGLIST.toml
+//   4. removes the journal, at which point the transaction is committed,
+//      and deletes the old files
+//
+// If any of the steps fails, or if pkgman is interrupted before the journal is
+// removed, the transaction is rolled back using the journal (see recover()):
+// the old files are moved back, the new files are removed and PKGLIST.toml and
+// the manifests are restored
+#[derive(Default)]
+pub struct Transaction {
+    staged:    Vec<PathBuf>,
+
+    // files to remove with the prefix they were installed to
+    removed:   Vec<(PathBuf, PathBuf)>,
+    committed: bool
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    path.with_file_name(format!(
+        ".{}.{}",
+        path.file_name().unwrap().to_string_lossy(),
+        suffix
+    ))
+}
+
+fn staged_path(path: &Path) -> PathBuf {
+    sibling(path, "pkgman-new")
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    sibling(path, "pkgman-old")
+}
+
+// true also for symbolic links that point to nothing
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+fn rename(from: &Path, to: &Path) -> Result<(), InstallError> {
+    match fs::rename(from, to) {
+        Ok(_)  => Ok(()),
+        Err(_) => Err(InstallError::WriteError(to.display().to_string()))
+    }
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    fn prepare(&mut self, path: &Path) -> Result<PathBuf, InstallError> {
+        let staged = staged_path(path);
+
+        // a file can't replace a directory
+        if fs::symlink_metadata(path).map(|meta| meta.is_dir()).unwrap_or(false) {
+            return Err(InstallError::WriteError(path.display().to_string()));
+        }
+
+        if let Some(dir) = path.parent() {
+            if fs::create_dir_all(dir).is_err() {
+                return Err(InstallError::WriteError(path.display().to_string()));
+            }
+        }
+
+        let _ = fs::remove_file(&staged);
+        self.staged.push(path.to_path_buf());
+
+        Ok(staged)
+    }
+
+    // Install the contents read from `data` to `path` with permissions `mode`
+    // when the transaction is committed
+    pub fn stage_file<R: Read>(&mut self, path: &Path, mut data: R, mode: u32) -> Result<(), InstallError> {
+        let staged = self.prepare(path)?;
+
+        let res = File::create(&staged)
+            .and_then(|mut f| io::copy(&mut data, &mut f).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::set_permissions(&staged, fs::Permissions::from_mode(mode)));
+
+        match res {
+            Ok(_)  => Ok(()),
+            Err(_) => Err(InstallError::WriteError(path.display().to_string()))
+        }
+    }
+
+    // Install a symbolic link pointing to `target` to `path` when the transaction is committed
+    pub fn stage_link(&mut self, path: &Path, target: &str) -> Result<(), InstallError> {
+        let staged = self.prepare(path)?;
+
+        match std::os::unix::fs::symlink(target, &staged) {
+            Ok(_)  => Ok(()),
+            Err(_) => Err(InstallError::WriteError(path.display().to_string()))
+        }
+    }
+
+    // Remove `path` when the transaction is committed
+    //
+    // Parent directories of `path` that become empty are removed up to `prefix`
+    pub fn remove(&mut self, prefix: &Path, path: &Path) {
+        if exists(path) {
+            self.removed.push((prefix.to_path_buf(), path.to_path_buf()));
+        }
+    }
+
+    // Move the staged files into place, remove the removed files and save
+    // `pkgs` as the new PKGLIST.toml together with the new `manifests` of
+    // the installed packages. Manifests of `dropped` packages are removed
+    pub fn commit(
+        mut self,
+        pkgs: HashMap<String, parser::PkgInfo>,
+        manifests: Vec<parser::Manifest>,
+        dropped: Vec<String>
+    ) -> Result<(), Error> {
+
+        let journal = self.journal(&manifests, &dropped)?;
+
+        parser::save_journal(&journal)?;
+
+        let res = self.apply(pkgs, manifests, dropped)
+            .and_then(|_| Ok(parser::remove_journal()?));
+
+        if let Err(err) = res {
+            println!("Transaction failed, rolling back: {}", err);
+            rollback(&journal)?;
+            return Err(err);
+        }
+
+        self.committed = true;
+
+        for file in journal.files.iter() {
+            let _ = fs::remove_file(backup_path(Path::new(&file.path)));
+        }
+
+        for (prefix, path) in self.removed.iter() {
+            let mut dir = path.parent();
+
+            while let Some(cur) = dir {
+                if cur == prefix || fs::remove_dir(cur).is_err() {
+                    break;
+                }
+                dir = cur.parent();
+            }
+        }
+
+        Ok(())
+    }
+
+    // state that is restored if the transaction is rolled back, see commit()
+    fn journal(&self, manifests: &[parser::Manifest], dropped: &[String]) -> Result<parser::Journal, Error> {
+        let mut journal = parser::Journal {
+            pkglist: parser::get_contents(&parser::expand("PKGLIST.toml"))?,
+            ..parser::Journal::default()
+        };
+
+        for name in manifests.iter().map(|m| &m.name).chain(dropped.iter()) {
+            journal.manifests.push(parser::JournalManifest {
+                name:     name.clone(),
+                contents: parser::get_contents(&parser::manifest_path(name)?)?
+            });
+        }
+
+        for path in self.staged.iter().chain(self.removed.iter().map(|(_, path)| path)) {
+            // a leftover from an earlier transaction would be restored by a rollback
+            let _ = fs::remove_file(backup_path(path));
+
+            journal.files.push(parser::JournalFile {
+                path:    path.display().to_string(),
+                existed: exists(path)
+            });
+        }
+
+        Ok(journal)
+    }
+
+    fn apply(
+        &self,
+        pkgs: HashMap<String, parser::PkgInfo>,
+        manifests: Vec<parser::Manifest>,
+        dropped: Vec<String>
+    ) -> Result<(), Error> {
+
+        for path in self.staged.iter() {
+            if exists(path) {
+                rename(path, &backup_path(path))?;
+            }
+            rename(&staged_path(path), path)?;
+        }
+
+        for (_, path) in self.removed.iter() {
+            rename(path, &backup_path(path))?;
+        }
+
+        for manifest in manifests.iter() {
+            parser::save_manifest(manifest)?;
+        }
+
+        for name in dropped.iter() {
+            parser::remove_manifest(name)?;
+        }
+
+        parser::save_pkgs(&parser::expand("PKGLIST.toml"), pkgs)?;
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    // remove the staged files of a transaction that was never committed
+    fn drop(&mut self) {
+        if !self.committed {
+            for path in self.staged.iter() {
+                let _ = fs::remove_file(staged_path(path));
+            }
+        }
+    }
+}
+
+// Restore the state saved in `journal`
+//
+// Everything that can be restored is restored even if some part fails, and the
+// journal is kept in that case so that recover() can retry the rollback
+fn rollback(journal: &parser::Journal) -> Result<(), Error> {
+    let mut res: Result<(), Error> = Ok(());
+
+    for file in journal.files.iter().rev() {
+        let path   = Path::new(&file.path);
+        let backup = backup_path(path);
+
+        if exists(&backup) {
+            if let Err(err) = rename(&backup, path) {
+                res = res.and(Err(Error::Install(err)));
+            }
+        } else if !file.existed {
+            let _ = fs::remove_file(path);
+        }
+
+        let _ = fs::remove_file(staged_path(path));
+    }
+
+    for manifest in journal.manifests.iter() {
+        let restored = parser::manifest_path(&manifest.name)
+            .and_then(|path| parser::restore_contents(&path, &manifest.contents));
+
+        res = res.and(restored.map_err(Error::Parser));
+    }
+
+    res = res.and(
+        parser::restore_contents(&parser::expand("PKGLIST.toml"), &journal.pkglist).map_err(Error::Parser)
+    );
+
+    res?;
+    Ok(parser::remove_journal()?)
+}
+
+// Roll back a transaction that was interrupted before it was committed
+//
+// Must be called with the lock of the package database held
+pub fn recover() -> Result<(), Error> {
+    if let Some(journal) = parser::get_journal()? {
+        println!("Rolling back an interrupted transaction");
+        rollback(&journal)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn manifest(name: &str, prefix: &Path, files: &[&str]) -> parser::Manifest {
+        parser::Manifest {
+            name:       name.to_string(),
+            version:    "1.0".to_string(),
+            prefix:     prefix.display().to_string(),
+            dependency: false,
+            files:      files.iter().map(|path| parser::ManifestFile {
+                path:   path.to_string(),
+                sha256: String::new(),
+                link:   String::new()
+            }).collect()
+        }
+    }
+
+    fn pkgs(names: &[&str]) -> HashMap<String, parser::PkgInfo> {
+        names.iter().map(|name| (name.to_string(), testing::pkginfo(name, "1.0"))).collect()
+    }
+
+    fn read(path: &Path) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    // prefix with bin/old and bin/replaced of package "old" installed
+    fn installed(home: &Path) -> PathBuf {
+        let prefix = home.join("prefix");
+
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin/old"), "old").unwrap();
+        fs::write(prefix.join("bin/replaced"), "old").unwrap();
+
+        parser::save_manifest(&manifest("old", &prefix, &["bin/old", "bin/replaced"])).unwrap();
+        parser::save_pkgs(&parser::expand("PKGLIST.toml"), pkgs(&["old"])).unwrap();
+        prefix
+    }
+
+    // transaction replacing package "old" with package "new"
+    fn replace(prefix: &Path) -> Transaction {
+        let mut txn = Transaction::new();
+
+        txn.stage_file(&prefix.join("bin/replaced"), "new".as_bytes(), 0o755).unwrap();
+        txn.stage_file(&prefix.join("share/new/file"), "new".as_bytes(), 0o644).unwrap();
+        txn.remove(prefix, &prefix.join("bin/old"));
+        txn
+    }
+
+    fn assert_rolled_back(prefix: &Path) {
+        assert_eq!(read(&prefix.join("bin/old")), Some("old".to_string()));
+        assert_eq!(read(&prefix.join("bin/replaced")), Some("old".to_string()));
+        assert!(!exists(&prefix.join("share/new/file")));
+        assert!(!exists(&staged_path(&prefix.join("bin/replaced"))));
+        assert!(!exists(&backup_path(&prefix.join("bin/replaced"))));
+
+        assert_eq!(parser::get_pkgs(&parser::expand("PKGLIST.toml")).unwrap().keys().collect::<Vec<_>>(), vec!["old"]);
+        assert!(parser::get_manifest("old").is_ok());
+        assert!(parser::get_manifest("new").is_err());
+        assert!(parser::get_journal().unwrap().is_none());
+    }
+
+    #[test]
+    fn commit_applies_all_changes() {
+        let home   = testing::home();
+        let prefix = installed(&home);
+
+        replace(&prefix).commit(
+            pkgs(&["new"]),
+            vec![manifest("new", &prefix, &["bin/replaced", "share/new/file"])],
+            vec!["old".to_string()]
+        ).unwrap();
+
+        assert!(!exists(&prefix.join("bin/old")));
+        assert_eq!(read(&prefix.join("bin/replaced")), Some("new".to_string()));
+        assert_eq!(read(&prefix.join("share/new/file")), Some("new".to_string()));
+        assert_eq!(fs::metadata(prefix.join("bin/replaced")).unwrap().permissions().mode() & 0o777, 0o755);
+        assert!(!exists(&backup_path(&prefix.join("bin/replaced"))));
+
+        assert!(parser::get_pkgs(&parser::expand("PKGLIST.toml")).unwrap().contains_key("new"));
+        assert!(parser::get_manifest("old").is_err());
+        assert!(parser::get_journal().unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_commit_is_rolled_back() {
+        let home   = testing::home();
+        let prefix = installed(&home);
+        let txn    = replace(&prefix);
+
+        // the second staged file can't be moved into place
+        fs::remove_file(staged_path(&prefix.join("share/new/file"))).unwrap();
+
+        assert!(txn.commit(
+            pkgs(&["new"]),
+            vec![manifest("new", &prefix, &["bin/replaced", "share/new/file"])],
+            vec!["old".to_string()]
+        ).is_err());
+
+        assert_rolled_back(&prefix);
+    }
+
+    #[test]
+    fn crash_mid_commit_is_rolled_back() {
+        let home      = testing::home();
+        let prefix    = installed(&home);
+        let txn       = replace(&prefix);
+        let manifests = vec![manifest("new", &prefix, &["bin/replaced", "share/new/file"])];
+        let dropped   = vec!["old".to_string()];
+
+        parser::save_journal(&txn.journal(&manifests, &dropped).unwrap()).unwrap();
+
+        // pkgman is killed after moving the first file into place
+        let replaced = prefix.join("bin/replaced");
+        rename(&replaced, &backup_path(&replaced)).unwrap();
+        rename(&staged_path(&replaced), &replaced).unwrap();
+        parser::save_manifest(&manifests[0]).unwrap();
+        std::mem::forget(txn);
+
+        assert_eq!(read(&replaced), Some("new".to_string()));
+
+        recover().unwrap();
+        assert_rolled_back(&prefix);
+    }
+
+    #[test]
+    fn crash_before_journal_removal_is_rolled_back() {
+        let home      = testing::home();
+        let prefix    = installed(&home);
+        let txn       = replace(&prefix);
+        let manifests = vec![manifest("new", &prefix, &["bin/replaced", "share/new/file"])];
+        let dropped   = vec!["old".to_string()];
+
+        parser::save_journal(&txn.journal(&manifests, &dropped).unwrap()).unwrap();
+        txn.apply(pkgs(&["new"]), manifests, dropped).unwrap();
+        std::mem::forget(txn);
+
+        recover().unwrap();
+        assert_rolled_back(&prefix);
+    }
+
+    #[test]
+    fn commit_without_file_changes() {
+        let home   = testing::home();
+        let prefix = installed(&home);
+        let mut manifest = manifest("old", &prefix, &["bin/old", "bin/replaced"]);
+
+        manifest.dependency = true;
+        Transaction::new().commit(pkgs(&["old"]), vec![manifest], Vec::new()).unwrap();
+
+        assert!(parser::get_manifest("old").unwrap().dependency);
+        assert_eq!(read(&prefix.join("bin/old")), Some("old".to_string()));
+    }
+
+    #[test]
+    fn dropped_transaction_removes_staged_files() {
+        let home   = testing::home();
+        let prefix = installed(&home);
+
+        drop(replace(&prefix));
+
+        assert!(!exists(&staged_path(&prefix.join("bin/replaced"))));
+        assert!(!exists(&staged_path(&prefix.join("share/new/file"))));
+        assert_eq!(read(&prefix.join("bin/replaced")), Some("old".to_string()));
+    }
+
+    #[test]
+    fn file_does_not_replace_directory() {
+        let home   = testing::home();
+        let prefix = installed(&home);
+
+        assert!(Transaction::new().stage_file(&prefix.join("bin"), "new".as_bytes(), 0o644).is_err());
+    }
+}
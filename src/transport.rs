@@ -0,0 +1,390 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::os::unix::fs::FileTypeExt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::ipfs;
+use crate::ipfs::IPFSError;
+use crate::settings;
+
+// Message received from a subscribed topic
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub from: String,
+    pub data: Vec<u8>
+}
+
+pub type Subscription = Pin<Box<dyn Stream<Item = Message>>>;
+
+// Publish/subscribe transport used to exchange the PST_* messages
+#[async_trait(?Send)]
+pub trait Transport {
+    // identifier of this node, used as the requester of queries
+    async fn peer_id(&self) -> String;
+
+    async fn publish(&self, topic: &str, data: &str) -> Result<(), IPFSError>;
+
+    // Subscribe to `topic`
+    //
+    // The subscription should be active when this function returns, so that
+    // answers to a query published right after subscribing are not lost.
+    // IpfsTransport can't guarantee this as the IPFS node only subscribes
+    // when the returned stream is polled for the first time
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, IPFSError>;
+}
+
+// Transport using the pubsub interface of the local IPFS node
+pub struct IpfsTransport {
+    client: ipfs_api::IpfsClient
+}
+
+impl IpfsTransport {
+    pub fn new() -> IpfsTransport {
+        IpfsTransport {
+            client: ipfs::get_client()
+        }
+    }
+}
+
+impl Default for IpfsTransport {
+    fn default() -> Self {
+        IpfsTransport::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for IpfsTransport {
+    async fn peer_id(&self) -> String {
+        match self.client.id(None).await {
+            Ok(res) => res.id,
+            Err(_)  => String::new()
+        }
+    }
+
+    async fn publish(&self, topic: &str, data: &str) -> Result<(), IPFSError> {
+        match self.client.pubsub_pub(topic, data).await {
+            Ok(_)  => Ok(()),
+            Err(err) => {
+                println!("Failed to publish to {}: {}", topic, err);
+                Err(IPFSError::UnableToConnect)
+            }
+        }
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, IPFSError> {
+        let sub = self.client
+            .pubsub_sub(topic, false)
+            .filter_map(|msg| async move {
+                match msg {
+                    Ok(msg) => {
+                        let data = base64::decode(msg.data?).ok()?;
+                        let from = msg.from.unwrap_or_else(|| "unknown".to_string());
+                        Some(Message { from, data })
+                    },
+                    Err(err) => {
+                        println!("Failed to read pubsub message: {}", err);
+                        None
+                    }
+                }
+            });
+
+        Ok(Box::pin(sub))
+    }
+}
+
+type Subscribers = Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<Message>>>>>;
+
+fn broadcast(subscribers: &Subscribers, topic: &str, msg: Message) {
+    if let Some(subs) = subscribers.lock().unwrap().get_mut(topic) {
+        subs.retain(|sub| sub.unbounded_send(msg.clone()).is_ok());
+    }
+}
+
+// In-process broadcast bus
+//
+// Every transport created with connect() receives all messages published
+// by any transport of the same bus, including its own messages, just like
+// IPFS pubsub does. This makes it possible to run a daemon and several
+// clients in one process
+#[derive(Clone, Default)]
+pub struct MemoryBus {
+    subscribers: Subscribers
+}
+
+impl MemoryBus {
+    pub fn new() -> MemoryBus {
+        MemoryBus::default()
+    }
+
+    pub fn connect(&self, peer_id: &str) -> MemoryTransport {
+        MemoryTransport {
+            bus:     self.clone(),
+            peer_id: peer_id.to_string()
+        }
+    }
+}
+
+pub struct MemoryTransport {
+    bus:     MemoryBus,
+    peer_id: String
+}
+
+#[async_trait(?Send)]
+impl Transport for MemoryTransport {
+    async fn peer_id(&self) -> String {
+        self.peer_id.clone()
+    }
+
+    async fn publish(&self, topic: &str, data: &str) -> Result<(), IPFSError> {
+        broadcast(&self.bus.subscribers, topic, Message {
+            from: self.peer_id.clone(),
+            data: data.as_bytes().to_vec()
+        });
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, IPFSError> {
+        let (tx, rx) = mpsc::unbounded();
+
+        self.bus.subscribers
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .push(tx);
+
+        Ok(Box::pin(rx))
+    }
+}
+
+// Unix socket transport for running the daemon and clients as separate local processes
+//
+// All messages go through a broker listening on a Unix socket (see unix_broker()).
+// The protocol is line-based, data is base64-encoded:
+//
+//   client -> broker: SUB <topic>
+//   broker -> client: OK
+//   broker -> client: MSG <from> <data>     (for each message published to <topic>)
+//
+//   client -> broker: PUB <topic> <from> <data>
+pub struct UnixTransport {
+    path:    String,
+    peer_id: String
+}
+
+impl UnixTransport {
+    pub fn new(path: &str) -> UnixTransport {
+        UnixTransport {
+            path:    path.to_string(),
+            peer_id: format!("unix-{}", std::process::id())
+        }
+    }
+
+    async fn connect(&self) -> Result<UnixStream, IPFSError> {
+        match UnixStream::connect(&self.path).await {
+            Ok(stream) => Ok(stream),
+            Err(err) => {
+                println!("Failed to connect to {}: {}", self.path, err);
+                Err(IPFSError::UnableToConnect)
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for UnixTransport {
+    async fn peer_id(&self) -> String {
+        self.peer_id.clone()
+    }
+
+    async fn publish(&self, topic: &str, data: &str) -> Result<(), IPFSError> {
+        let mut stream = self.connect().await?;
+        let line = format!("PUB {} {} {}\n", topic, self.peer_id, base64::encode(data));
+
+        match stream.write_all(line.as_bytes()).await {
+            Ok(_)  => Ok(()),
+            Err(_) => Err(IPFSError::UnableToConnect)
+        }
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, IPFSError> {
+        let mut stream = self.connect().await?;
+
+        if stream.write_all(format!("SUB {}\n", topic).as_bytes()).await.is_err() {
+            return Err(IPFSError::UnableToConnect);
+        }
+
+        let mut reader = BufReader::new(stream);
+        let mut ack    = String::new();
+
+        match reader.read_line(&mut ack).await {
+            Ok(_) if ack.trim_end() == "OK" => { },
+            _ => return Err(IPFSError::UnableToConnect)
+        }
+
+        let sub = reader
+            .lines()
+            .filter_map(|line| async move {
+                let line  = line.ok()?;
+                let mut parts = line.splitn(3, ' ');
+
+                if parts.next()? != "MSG" {
+                    return None;
+                }
+
+                let from = parts.next()?.to_string();
+                let data = base64::decode(parts.next()?).ok()?;
+
+                Some(Message { from, data })
+            });
+
+        Ok(Box::pin(sub))
+    }
+}
+
+async fn handle_unix_client(stream: UnixStream, subscribers: Subscribers) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let parts: Vec<&str> = line.splitn(4, ' ').collect();
+
+        match parts.as_slice() {
+            ["SUB", topic] => {
+                let (tx, mut rx) = mpsc::unbounded::<Message>();
+
+                subscribers
+                    .lock()
+                    .unwrap()
+                    .entry(topic.to_string())
+                    .or_default()
+                    .push(tx);
+
+                if writer.write_all(b"OK\n").await.is_err() {
+                    return;
+                }
+
+                // the connection is now used only for forwarding messages to the subscriber
+                while let Some(msg) = rx.next().await {
+                    let line = format!("MSG {} {}\n", msg.from, base64::encode(&msg.data));
+
+                    if writer.write_all(line.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                return;
+            },
+            ["PUB", topic, from, data] => {
+                if let Ok(data) = base64::decode(data) {
+                    broadcast(&subscribers, topic, Message {
+                        from: from.to_string(),
+                        data
+                    });
+                }
+            },
+            _ => {
+                println!("Invalid broker command: {}", line);
+                return;
+            }
+        }
+    }
+}
+
+// Run the broker of UnixTransport on socket `path`
+pub async fn unix_broker(path: &str) -> Result<(), IPFSError> {
+    // remove the socket left behind by a previous broker but nothing else
+    if let Ok(meta) = std::fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    let mut listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Failed to bind {}: {}", path, err);
+            return Err(IPFSError::UnableToConnect);
+        }
+    };
+
+    let subscribers = Subscribers::default();
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_unix_client(stream, subscribers.clone()));
+            },
+            Err(err) => {
+                println!("Failed to accept connection: {}", err);
+            }
+        }
+    }
+}
+
+// Create the transport configured in pkgman.toml
+pub fn from_settings(settings: &settings::Settings) -> Box<dyn Transport> {
+    match &settings.transport_socket {
+        Some(path) => Box::new(UnixTransport::new(path)),
+        None       => Box::new(IpfsTransport::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::time::Duration;
+
+    // Start a broker on a new socket and wait until it accepts connections
+    async fn start_broker() -> String {
+        let path   = testing::temp_dir().join("broker.sock").display().to_string();
+        let broker = path.clone();
+
+        actix_rt::spawn(async move {
+            unix_broker(&broker).await.unwrap();
+        });
+
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        path
+    }
+
+    #[actix_rt::test]
+    async fn unix_broker_round_trip() {
+        let path      = start_broker().await;
+        let publisher = UnixTransport::new(&path);
+        let client    = UnixTransport::new(&path);
+
+        let mut queries = client.subscribe("query").await.unwrap();
+        let mut answers = client.subscribe("answer").await.unwrap();
+
+        publisher.publish("query", "hello world\n").await.unwrap();
+        publisher.publish("other", "not subscribed").await.unwrap();
+        publisher.publish("answer", "42").await.unwrap();
+
+        let msg = queries.next().await.unwrap();
+        assert_eq!(msg.from, publisher.peer_id().await);
+        assert_eq!(msg.data, b"hello world\n");
+
+        // messages of other topics are not forwarded
+        assert_eq!(answers.next().await.unwrap().data, b"42");
+    }
+
+    #[actix_rt::test]
+    async fn unix_broker_keeps_other_files() {
+        let path = testing::temp_dir().join("broker.sock");
+
+        std::fs::write(&path, "data").unwrap();
+
+        assert!(unix_broker(path.to_str().unwrap()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+    }
+}
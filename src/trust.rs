@@ -0,0 +1,944 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::Error;
+use crate::crypto;
+use crate::history;
+use crate::ipfs;
+use crate::parser;
+use crate::resolve;
+use crate::settings;
+
+#[derive(Debug)]
+pub enum TrustError {
+    // pkgman.toml doesn't have any trust anchors
+    NoTrustAnchors,
+
+    // not a base64-encoded Ed25519 public key
+    InvalidKey(String),
+
+    // key is not in the keyring
+    UnknownKey(String),
+
+    // not a package name or a prefix of package names followed by `*`
+    InvalidPattern(String),
+
+    // keyring snapshot is not signed by a trust anchor
+    UnsignedSnapshot,
+
+    // keyring snapshot expired at the given time
+    ExpiredSnapshot(u64),
+
+    // local keyring expired at the given time, or was never updated if it's 0
+    ExpiredKeyring(u64),
+
+    // keyring snapshot is not newer than the current keyring
+    OldSnapshot { serial: u64, current: u64 }
+}
+
+impl fmt::Display for TrustError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrustError::NoTrustAnchors        => write!(f, "no trust anchors configured, use --init --root-key <key>"),
+            TrustError::InvalidKey(key)       => write!(f, "{}: not a valid public key", key),
+            TrustError::UnknownKey(key)       => write!(f, "{}: no such key in the keyring", key),
+            TrustError::InvalidPattern(pat)   => write!(f, "{}: not a package name or a package name prefix", pat),
+            TrustError::UnsignedSnapshot      => write!(f, "keyring snapshot is not signed by a trust anchor"),
+            TrustError::ExpiredSnapshot(time) => write!(f, "keyring snapshot expired at {}", history::format_time(*time)),
+            TrustError::ExpiredKeyring(0)     => write!(f, "no keyring snapshot, use --update-keyring"),
+            TrustError::ExpiredKeyring(time)  => {
+                write!(f, "keyring expired at {}, use --update-keyring", history::format_time(*time))
+            },
+            TrustError::OldSnapshot { serial, current } => {
+                write!(f, "keyring snapshot {} is not newer than the current keyring {}", serial, current)
+            }
+        }
+    }
+}
+
+impl From<TrustError> for Error {
+    fn from(err: TrustError) -> Self {
+        Error::Trust(err)
+    }
+}
+
+// Create a trust anchor of base64-encoded public key `key`
+//
+// The anchor is named after the key id of the key
+pub fn anchor(key: &str) -> Result<settings::TrustAnchor, TrustError> {
+    let valid = base64::decode(key).map(|raw| raw.len() == 32).unwrap_or(false);
+
+    match crypto::key_id(key) {
+        Some(id) if valid => Ok(settings::TrustAnchor { name: id, key: key.to_string() }),
+        _                 => Err(TrustError::InvalidKey(key.to_string()))
+    }
+}
+
+// Keys of the trust anchors configured in pkgman.toml
+pub fn anchors(settings: &settings::Settings) -> Result<Vec<String>, TrustError> {
+    if settings.trust_anchors.is_empty() {
+        return Err(TrustError::NoTrustAnchors);
+    }
+
+    Ok(settings.trust_anchors.iter().map(|anchor| anchor.key.clone()).collect())
+}
+
+// Check keyring snapshot `keyring` received from the network before it replaces
+// keyring `current`
+//
+// The snapshot must be signed by a trust anchor and must not have expired, and
+// its serial must be larger than the serial of `current` so that an older
+// snapshot, which may lack later revocations or maintainers, can't be replayed.
+// A snapshot with the same serial is accepted only if its contents are the same
+pub fn check_snapshot(
+    settings: &settings::Settings,
+    keyring: &parser::KeyringConfig,
+    current: &parser::KeyringConfig
+) -> Result<(), TrustError> {
+    if !anchors(settings)?.iter().any(|key| crypto::verify_keyring(keyring, key)) {
+        return Err(TrustError::UnsignedSnapshot);
+    }
+
+    if keyring.expires <= crypto::now() {
+        return Err(TrustError::ExpiredSnapshot(keyring.expires));
+    }
+
+    let newer = match keyring.serial.cmp(&current.serial) {
+        Ordering::Greater => true,
+        Ordering::Equal   => crypto::canonical_keyring(keyring) == crypto::canonical_keyring(current),
+        Ordering::Less    => false
+    };
+
+    if !newer {
+        return Err(TrustError::OldSnapshot { serial: keyring.serial, current: current.serial });
+    }
+
+    Ok(())
+}
+
+// Web of trust formed by the trust anchors and the certifications of keyring entries
+//
+// A maintainer key is trusted if there are at least `trust_min_paths` independent
+// certification paths from the trust anchors to it, each at most `trust_max_depth`
+// certifications long (see settings.rs). Two paths are independent if they have
+// no key in common other than the trust anchor and the maintainer key. The paths
+// are searched shortest first and a key used by one path is not used again, so
+// in some webs fewer independent paths are found than there are, never more
+//
+// A maintainer key can be revoked with a revocation record signed by the key
+// itself or by a trust anchor. Revocations are final: as the signing time of a
+// package is chosen by the signer, no signature or certification made by a
+// revoked key is accepted, whatever its time. The revocation time is only
+// shown to the user
+//
+// Signatures made by a maintainer key are accepted only within the validity
+// period of the key, which is taken from the entries of the key certified by
+// a trust anchor or a trusted key. A maintainer moving to a new key signs a
+// rotation statement with the previous key: the new key is then certified by
+// the keys certifying the previous key, and the previous key is not accepted
+// anymore. The signing time of a package is chosen by the signer, so both it
+// and the current time must be within the validity period and before the
+// rotation, and packages signed by a key are rejected once the key expires or
+// is rotated until they are signed again with a valid key
+pub struct Web {
+    anchors:     Vec<settings::TrustAnchor>,
+    entries:     Vec<parser::KeyringEntry>,
+
+    // keys certifying each entry, with a valid certification or rotation statement
+    certified:   Vec<Vec<String>>,
+
+    // keys certifying each key, with a valid certification
+    certifiers:  HashMap<String, Vec<String>>,
+
+    // earliest rotation time of each rotated key
+    rotated:     HashMap<String, u64>,
+
+    // keys each key was rotated from
+    previous:    HashMap<String, Vec<String>>,
+    owners:      Vec<parser::Ownership>,
+
+    // valid revocations and the earliest revocation time of each revoked key
+    revocations: Vec<parser::Revocation>,
+    revoked:     HashMap<String, u64>,
+    max_depth:   usize,
+    min_paths:   usize,
+
+    min_signatures: usize,
+    policies:       Vec<settings::SignaturePolicy>
+}
+
+impl Web {
+    pub fn new(settings: &settings::Settings, keyring: parser::KeyringConfig) -> Result<Web, TrustError> {
+        if settings.trust_anchors.is_empty() {
+            return Err(TrustError::NoTrustAnchors);
+        }
+
+        let anchors = settings.trust_anchors.clone();
+        let entries = keyring.signers;
+        let known: Vec<&String> = anchors
+            .iter()
+            .map(|anchor| &anchor.key)
+            .chain(entries.iter().map(|entry| &entry.key))
+            .collect();
+
+        let mut revocations: Vec<parser::Revocation> = Vec::new();
+        let mut revoked: HashMap<String, u64> = HashMap::new();
+
+        for rev in keyring.revocations {
+            // anchors are only trusted through pkgman.toml and can't be revoked from the network
+            let revokers = anchors.iter().map(|anchor| &anchor.key).chain(std::iter::once(&rev.key));
+            let valid    = !anchors.iter().any(|anchor| anchor.key == rev.key)
+                && revokers.into_iter().any(|key| crypto::verify_revocation(&rev, key));
+
+            if !valid {
+                continue;
+            }
+
+            let time = revoked.entry(rev.key.clone()).or_insert(rev.timestamp);
+            *time = (*time).min(rev.timestamp);
+
+            revocations.push(rev);
+        }
+
+        let mut certified: Vec<Vec<String>> = Vec::new();
+        let mut certifiers: HashMap<String, Vec<String>> = HashMap::new();
+        let mut rotations: Vec<(&String, &String, u64)> = Vec::new();
+        let mut rotated: HashMap<String, u64> = HashMap::new();
+
+        for entry in entries.iter() {
+            let mut list: Vec<String> = Vec::new();
+            let sig = base64::decode(&entry.signature).unwrap_or_default();
+
+            // entries created before certifications were added are signed by an anchor
+            for anchor in anchors.iter() {
+                if !entry.signature.is_empty() && crypto::verify(&anchor.key, entry.key.as_bytes(), &sig) {
+                    list.push(anchor.key.clone());
+                }
+            }
+
+            for cert in entry.certifications.iter() {
+                for key in known.iter() {
+                    let usable = **key != entry.key && !list.contains(key) && !revoked.contains_key(*key);
+
+                    if usable && crypto::verify_certification(entry, cert, key) {
+                        list.push((*key).clone());
+                    }
+                }
+            }
+
+            let all = certifiers.entry(entry.key.clone()).or_default();
+
+            for key in list.iter() {
+                if !all.contains(key) {
+                    all.push(key.clone());
+                }
+            }
+
+            // anchors are only changed through pkgman.toml
+            if let Some(rotation) = &entry.rotation {
+                let usable = !anchors.iter().any(|anchor| anchor.key == rotation.key)
+                    && !revoked.contains_key(&rotation.key);
+
+                if usable && crypto::verify_rotation(entry) {
+                    rotations.push((&rotation.key, &entry.key, rotation.timestamp));
+                    list.push(rotation.key.clone());
+
+                    let time = rotated.entry(rotation.key.clone()).or_insert(rotation.timestamp);
+                    *time = (*time).min(rotation.timestamp);
+                }
+            }
+
+            certified.push(list);
+        }
+
+        // in order of rotation, so that a key rotated more than once
+        // is certified by the certifiers of the first key
+        rotations.sort_by_key(|(_, _, timestamp)| *timestamp);
+
+        let mut previous: HashMap<String, Vec<String>> = HashMap::new();
+
+        for (old, new, _) in rotations {
+            previous.entry(new.clone()).or_default().push(old.clone());
+
+            let inherited = certifiers.get(old).cloned().unwrap_or_default();
+            let list      = certifiers.entry(new.clone()).or_default();
+
+            for key in inherited {
+                if key != *new && !list.contains(&key) {
+                    list.push(key);
+                }
+            }
+        }
+
+        Ok(Web {
+            anchors,
+            entries,
+            certified,
+            certifiers,
+            rotated,
+            previous,
+            owners: keyring.owners,
+            revocations,
+            revoked,
+            max_depth: settings.trust_max_depth,
+            min_paths: settings.trust_min_paths,
+            min_signatures: settings.min_signatures,
+            policies:       settings.signature_policies.clone()
+        })
+    }
+
+    pub fn is_anchor(&self, key: &str) -> bool {
+        self.anchors.iter().any(|anchor| anchor.key == key)
+    }
+
+    pub fn anchors(&self) -> &[settings::TrustAnchor] {
+        &self.anchors
+    }
+
+    pub fn entries(&self) -> &[parser::KeyringEntry] {
+        &self.entries
+    }
+
+    pub fn min_paths(&self) -> usize {
+        self.min_paths
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    // Name of the anchor or the maintainer of `key`
+    pub fn name(&self, key: &str) -> String {
+        let anchor = self.anchors.iter().find(|anchor| anchor.key == key).map(|anchor| &anchor.name);
+        let entry  = self.entries.iter().find(|entry| entry.key == key).map(|entry| &entry.name);
+
+        match anchor.or(entry) {
+            Some(name) => name.clone(),
+            None       => crypto::key_id(key).unwrap_or_default()
+        }
+    }
+
+    // Independent certification paths to `key`, each path starts from
+    // a trust anchor and ends with `key`
+    pub fn paths(&self, key: &str) -> Vec<Vec<String>> {
+        let mut paths: Vec<Vec<String>> = Vec::new();
+        let mut used: HashSet<String> = HashSet::new();
+
+        while let Some(path) = self.shortest_path(key, &used, &paths) {
+            used.extend(path[1..path.len() - 1].iter().cloned());
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    // breadth-first search from `key` towards the anchors, avoiding the keys
+    // of `used` and the direct certifications used by `found` already
+    fn shortest_path(&self, key: &str, used: &HashSet<String>, found: &[Vec<String>]) -> Option<Vec<String>> {
+        let mut next: HashMap<String, String> = HashMap::new();
+        let mut queue: VecDeque<(String, usize)> = VecDeque::new();
+
+        queue.push_back((key.to_string(), 0));
+
+        while let Some((cur, depth)) = queue.pop_front() {
+            for certifier in self.certifiers.get(&cur).into_iter().flatten() {
+                if self.is_anchor(certifier) {
+                    let direct = cur == key && found.iter().any(|path| path.len() == 2 && path[0] == *certifier);
+
+                    if direct {
+                        continue;
+                    }
+
+                    let mut path = vec![certifier.clone(), cur.clone()];
+
+                    while let Some(step) = next.get(path.last().unwrap()) {
+                        path.push(step.clone());
+                    }
+
+                    return Some(path);
+                }
+
+                let unseen = certifier != key && !used.contains(certifier) && !next.contains_key(certifier);
+
+                if unseen && depth + 1 < self.max_depth {
+                    next.insert(certifier.clone(), cur.clone());
+                    queue.push_back((certifier.clone(), depth + 1));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn trusted(&self, key: &str) -> bool {
+        self.is_anchor(key) || self.paths(key).len() >= self.min_paths.max(1)
+    }
+
+    // Time `key` was revoked at, if it has been revoked
+    pub fn revoked(&self, key: &str) -> Option<u64> {
+        self.revoked.get(key).cloned()
+    }
+
+    // Valid revocation records, to be kept in the keyring
+    pub fn revocations(&self) -> &[parser::Revocation] {
+        &self.revocations
+    }
+
+    // Time `key` was rotated to another key at, if it has been rotated
+    pub fn rotated(&self, key: &str) -> Option<u64> {
+        self.rotated.get(key).cloned()
+    }
+
+    // Validity periods of `key` as (valid_from, valid_until), zero if unlimited
+    //
+    // Only the entries certified by a trust anchor or a trusted key other than
+    // `key` itself count, so that an entry certified by an untrusted key can't
+    // extend the validity of a trusted key
+    pub fn validity(&self, key: &str) -> Vec<(u64, u64)> {
+        self.entries
+            .iter()
+            .zip(self.certified.iter())
+            .filter(|(entry, certified)| entry.key == key && certified.iter().any(|other| other != key && self.trusted(other)))
+            .map(|(entry, _)| (entry.valid_from, entry.valid_until))
+            .collect()
+    }
+
+    // whether signatures of maintainer key `key` made at `timestamp` are
+    // accepted, without a timestamp only unlimited keys are accepted
+    //
+    // The signing time is chosen by the signer, so the key must also be within
+    // its validity period and not rotated at the current time. Otherwise whoever
+    // holds an expired or rotated key could backdate new signatures
+    fn valid_at(&self, key: &str, timestamp: Option<u64>) -> bool {
+        let now = crypto::now();
+
+        if self.revoked(key).is_some() {
+            return false;
+        }
+
+        if let Some(time) = self.rotated(key) {
+            if now >= time || timestamp.is_none_or(|timestamp| timestamp >= time) {
+                return false;
+            }
+        }
+
+        self.validity(key).iter().any(|(from, until)| {
+            let within = |time: u64| time >= *from && (*until == 0 || time < *until);
+
+            within(now) && match timestamp {
+                Some(timestamp) => within(timestamp),
+                None            => *from == 0 && *until == 0
+            }
+        })
+    }
+
+    // Keys that are valid for signatures made at `timestamp`: the trust anchors
+    // and the trusted maintainer keys that aren't revoked, were valid and not
+    // rotated then, and are still valid and not rotated now
+    //
+    // Without a timestamp, only the keys that have never been revoked or rotated
+    // and have no validity period are valid
+    pub fn keys_at(&self, timestamp: Option<u64>) -> Vec<String> {
+        let mut keys: Vec<String> = self.anchors.iter().map(|anchor| anchor.key.clone()).collect();
+
+        for entry in self.entries.iter() {
+            if !keys.contains(&entry.key) && self.trusted(&entry.key) && self.valid_at(&entry.key, timestamp) {
+                keys.push(entry.key.clone());
+            }
+        }
+
+        keys
+    }
+
+    // Package names maintainer key `key` may publish, including the
+    // package names of the keys it was rotated from
+    pub fn packages(&self, key: &str) -> Vec<String> {
+        let mut keys = vec![key.to_string()];
+        let mut i    = 0;
+
+        while i < keys.len() {
+            for old in self.previous.get(&keys[i]).into_iter().flatten() {
+                if !keys.contains(old) {
+                    keys.push(old.clone());
+                }
+            }
+            i += 1;
+        }
+
+        self.owners
+            .iter()
+            .filter(|owner| keys.contains(&owner.key))
+            .flat_map(|owner| owner.packages.iter().cloned())
+            .collect()
+    }
+
+    // Whether `key` may publish package `name`, trust anchors may publish any package
+    pub fn authorized(&self, key: &str, name: &str) -> bool {
+        self.is_anchor(key) || self.packages(key).iter().any(|pattern| matches(pattern, name))
+    }
+
+    // Number of distinct keys that must sign package `name`
+    //
+    // The largest number of the signature policies matching `name`,
+    // or `min_signatures` if there's none (see settings.rs)
+    pub fn min_signatures(&self, name: &str) -> usize {
+        self.policies
+            .iter()
+            .filter(|policy| policy.packages.iter().any(|pattern| matches(pattern, name)))
+            .map(|policy| policy.min_signatures)
+            .max()
+            .unwrap_or(self.min_signatures)
+    }
+
+    // first key of the rotations leading to `key`, so that the keys of
+    // a maintainer who rotated their key count as one signer
+    fn identity(&self, key: &str) -> String {
+        let mut keys: Vec<&String> = Vec::new();
+        let mut cur = key.to_string();
+
+        while let Some(old) = self.previous.get(&cur).and_then(|previous| previous.iter().min()) {
+            if keys.contains(&old) {
+                break;
+            }
+
+            keys.push(old);
+            cur = old.clone();
+        }
+
+        cur
+    }
+
+    // Verify the signature of `pkg` against the keys that were valid when it was
+    // signed, and check that the signing key may publish the package
+    //
+    // A package can stand in for the packages it provides or replaces, so the
+    // signing key must be authorized for their names as well
+    //
+    // Packages that need more than one signature (see min_signatures()) must
+    // also have valid co-signatures by enough other authorized keys, each
+    // checked against the keys that were valid when it was made
+    //
+    // Legacy entries don't have a signing time (see crypto::verify_pkginfo)
+    pub fn verify_pkginfo(&self, pkg: &parser::PkgInfo, allow_legacy: bool) -> Result<(), ipfs::IPFSError> {
+        let timestamp = if pkg.signer.is_empty() { None } else { Some(pkg.timestamp) };
+        let keys      = self.keys_at(timestamp);
+
+        crypto::verify_pkginfo(pkg, &keys, allow_legacy)?;
+
+        let names: Vec<String> = pkg.provides
+            .iter()
+            .chain(pkg.replaces.iter())
+            .filter_map(|val| resolve::parse_constraint(val).ok())
+            .map(|relation| relation.name)
+            .chain(std::iter::once(pkg.name.clone()))
+            .collect();
+
+        let authorized = |key: &String| names.iter().all(|name| self.authorized(key, name));
+
+        let signer = match keys
+            .iter()
+            .filter(|key| authorized(key))
+            .find(|key| crypto::verify_pkginfo(pkg, &[(*key).clone()], allow_legacy).is_ok()) {
+            Some(signer) => signer,
+            None         => return Err(ipfs::IPFSError::Unauthorized)
+        };
+
+        let required    = self.min_signatures(&pkg.name);
+        let mut signers = vec![self.identity(signer)];
+
+        for cosig in pkg.cosignatures.iter() {
+            if signers.len() >= required {
+                break;
+            }
+
+            let cosigner = self.keys_at(Some(cosig.timestamp))
+                .into_iter()
+                .filter(|key| authorized(key))
+                .find(|key| crypto::verify_cosignature(pkg, cosig, key));
+
+            if let Some(identity) = cosigner.map(|key| self.identity(&key)) {
+                if !signers.contains(&identity) {
+                    signers.push(identity);
+                }
+            }
+        }
+
+        match signers.len() >= required {
+            true  => Ok(()),
+            false => Err(ipfs::IPFSError::MissingSignatures)
+        }
+    }
+}
+
+// package name `name` matches `pattern`, which is a package name
+// or a prefix of package names followed by `*`
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None         => pattern == name
+    }
+}
+
+// Web of trust of the configured trust anchors and the local keyring
+//
+// The local keyring must not have expired, otherwise revocations published
+// after it was fetched could be missed
+pub fn load() -> Result<Web, Error> {
+    let keyring = parser::get_keyring()?;
+
+    if keyring.expires <= crypto::now() {
+        return Err(Error::Trust(TrustError::ExpiredKeyring(keyring.expires)));
+    }
+
+    Ok(Web::new(&settings::get_settings()?, keyring)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use ring::signature::Ed25519KeyPair;
+
+    // trust anchor and a maintainer certified by it
+    struct Fixture {
+        anchor:     Ed25519KeyPair,
+        anchor_key: String,
+        maint:      Ed25519KeyPair,
+        maint_key:  String,
+        settings:   settings::Settings,
+        keyring:    parser::KeyringConfig
+    }
+
+    fn fixture() -> Fixture {
+        let (anchor, anchor_key) = testing::keypair();
+        let (maint, maint_key)   = testing::keypair();
+        let settings = testing::settings(&[&anchor_key]);
+        let keyring  = parser::KeyringConfig {
+            signers: vec![testing::entry("alice", &maint_key, &[&anchor])],
+            owners:  vec![parser::Ownership { key: maint_key.clone(), packages: vec!["hello*".to_string()] }],
+            ..parser::KeyringConfig::default()
+        };
+
+        Fixture { anchor, anchor_key, maint, maint_key, settings, keyring }
+    }
+
+    // re-sign `pkg` with `keypair` claiming it was signed at `timestamp`
+    fn backdate(keypair: &Ed25519KeyPair, pkg: &mut parser::PkgInfo, timestamp: u64) {
+        pkg.timestamp = timestamp;
+        pkg.signature = base64::encode(keypair.sign(crypto::canonical_pkginfo(pkg).unwrap().as_bytes()));
+    }
+
+    fn snapshot(f: &Fixture, serial: u64, expires: u64) -> parser::KeyringConfig {
+        let mut keyring = parser::KeyringConfig { serial, expires, ..f.keyring.clone() };
+
+        crypto::sign_keyring(&f.anchor, &mut keyring).unwrap();
+        keyring
+    }
+
+    #[test]
+    fn certified_key_is_trusted() {
+        let f   = fixture();
+        let web = Web::new(&f.settings, f.keyring.clone()).unwrap();
+
+        assert!(web.trusted(&f.anchor_key));
+        assert!(web.trusted(&f.maint_key));
+        assert_eq!(web.paths(&f.maint_key), vec![vec![f.anchor_key.clone(), f.maint_key.clone()]]);
+    }
+
+    #[test]
+    fn uncertified_key_is_not_trusted() {
+        let mut f      = fixture();
+        let (other, _) = testing::keypair();
+        let (_, key)   = testing::keypair();
+
+        // certified only by a key that isn't trusted itself
+        f.keyring.signers.push(testing::entry("mallory", &key, &[&other]));
+
+        let web = Web::new(&f.settings, f.keyring).unwrap();
+        assert!(!web.trusted(&key));
+    }
+
+    #[test]
+    fn trust_paths_are_limited_and_counted() {
+        let mut f    = fixture();
+        let (_, key) = testing::keypair();
+
+        // certified by the maintainer only
+        f.keyring.signers.push(testing::entry("bob", &key, &[&f.maint]));
+
+        let web = Web::new(&f.settings, f.keyring.clone()).unwrap();
+        assert_eq!(web.paths(&key), vec![vec![f.anchor_key.clone(), f.maint_key.clone(), key.clone()]]);
+        assert!(web.trusted(&key));
+
+        f.settings.trust_max_depth = 1;
+        assert!(!Web::new(&f.settings, f.keyring.clone()).unwrap().trusted(&key));
+
+        f.settings.trust_max_depth = 2;
+        f.settings.trust_min_paths = 2;
+        assert!(!Web::new(&f.settings, f.keyring.clone()).unwrap().trusted(&key));
+
+        // and by the trust anchor
+        f.keyring.signers.push(testing::entry("bob", &key, &[&f.anchor]));
+        assert!(Web::new(&f.settings, f.keyring).unwrap().trusted(&key));
+    }
+
+    #[test]
+    fn no_trust_anchors() {
+        let f = fixture();
+
+        assert!(matches!(
+            Web::new(&settings::Settings::default(), f.keyring),
+            Err(TrustError::NoTrustAnchors)
+        ));
+    }
+
+    #[test]
+    fn package_of_unknown_key_is_rejected() {
+        let f          = fixture();
+        let web        = Web::new(&f.settings, f.keyring.clone()).unwrap();
+        let (other, _) = testing::keypair();
+
+        assert!(web.verify_pkginfo(&testing::signed(&other, "hello", "1.0"), false).is_err());
+    }
+
+    #[test]
+    fn revoked_key_is_rejected_whatever_the_signing_time() {
+        let mut f = fixture();
+        let pkg   = testing::signed(&f.maint, "hello", "1.0");
+
+        // revoked after the package was signed
+        let mut later = f.keyring.clone();
+        later.revocations.push(crypto::revoke(&f.maint, &f.maint_key, pkg.timestamp + 10, "lost").unwrap());
+
+        let web = Web::new(&f.settings, later).unwrap();
+        assert_eq!(web.revoked(&f.maint_key), Some(pkg.timestamp + 10));
+        assert!(web.verify_pkginfo(&pkg, false).is_err());
+
+        // revoked by a trust anchor, and a package claiming to be signed before
+        f.keyring.revocations.push(crypto::revoke(&f.anchor, &f.maint_key, pkg.timestamp, "compromised").unwrap());
+
+        let mut pkg = testing::signed(&f.maint, "hello", "1.1");
+        let time    = pkg.timestamp - 100;
+        backdate(&f.maint, &mut pkg, time);
+
+        let web = Web::new(&f.settings, f.keyring).unwrap();
+        assert!(web.verify_pkginfo(&pkg, false).is_err());
+    }
+
+    #[test]
+    fn revocation_by_other_maintainer_is_ignored() {
+        let mut f        = fixture();
+        let (other, key) = testing::keypair();
+
+        f.keyring.signers.push(testing::entry("bob", &key, &[&f.anchor]));
+        f.keyring.revocations.push(crypto::revoke(&other, &f.maint_key, 0, "spite").unwrap());
+
+        let web = Web::new(&f.settings, f.keyring).unwrap();
+        assert_eq!(web.revoked(&f.maint_key), None);
+        assert!(web.revocations().is_empty());
+    }
+
+    #[test]
+    fn package_outside_validity_period_is_rejected() {
+        let mut f = fixture();
+        let now   = crypto::now();
+
+        let mut entry = testing::entry("alice", &f.maint_key, &[]);
+        entry.valid_from  = now - 100;
+        entry.valid_until = now - 10;
+        crypto::certify(&f.anchor, &mut entry).unwrap();
+        f.keyring.signers = vec![entry];
+
+        let web = Web::new(&f.settings, f.keyring).unwrap();
+        assert!(web.verify_pkginfo(&testing::signed(&f.maint, "hello", "1.0"), false).is_err());
+
+        // signed within the validity period, or claiming to be
+        let mut pkg = testing::signed(&f.maint, "hello", "1.0");
+        backdate(&f.maint, &mut pkg, now - 50);
+        assert!(web.verify_pkginfo(&pkg, false).is_err());
+    }
+
+    #[test]
+    fn package_within_validity_period_is_accepted() {
+        let mut f = fixture();
+        let now   = crypto::now();
+
+        let mut entry = testing::entry("alice", &f.maint_key, &[]);
+        entry.valid_from  = now - 100;
+        entry.valid_until = now + 100;
+        crypto::certify(&f.anchor, &mut entry).unwrap();
+        f.keyring.signers = vec![entry];
+
+        let web = Web::new(&f.settings, f.keyring).unwrap();
+        assert!(web.verify_pkginfo(&testing::signed(&f.maint, "hello", "1.0"), false).is_ok());
+
+        let mut pkg = testing::signed(&f.maint, "hello", "1.0");
+        backdate(&f.maint, &mut pkg, now - 200);
+        assert!(web.verify_pkginfo(&pkg, false).is_err());
+    }
+
+    #[test]
+    fn rotated_key_inherits_trust_and_packages() {
+        let mut f       = fixture();
+        let (next, key) = testing::keypair();
+        let mut entry   = testing::entry("alice", &key, &[]);
+        let mut old     = testing::signed(&f.maint, "hello", "1.0");
+
+        backdate(&f.maint, &mut old, crypto::now() - 20);
+        crypto::rotate(&f.maint, &mut entry, crypto::now() - 10).unwrap();
+        f.keyring.signers.push(entry);
+
+        let web = Web::new(&f.settings, f.keyring).unwrap();
+        assert!(web.trusted(&key));
+        assert!(web.verify_pkginfo(&testing::signed(&next, "hello", "1.1"), false).is_ok());
+
+        // the previous key isn't accepted after the rotation, even for
+        // packages signed before it, which must be signed again
+        assert!(web.verify_pkginfo(&testing::signed(&f.maint, "hello", "1.1"), false).is_err());
+        assert!(web.verify_pkginfo(&old, false).is_err());
+    }
+
+    #[test]
+    fn snapshot_signed_by_anchor_is_accepted() {
+        let f = fixture();
+        let current = snapshot(&f, 1, crypto::now() + 60);
+
+        assert!(check_snapshot(&f.settings, &snapshot(&f, 2, crypto::now() + 60), &current).is_ok());
+        assert!(check_snapshot(&f.settings, &current, &current).is_ok());
+    }
+
+    #[test]
+    fn unsigned_snapshot_is_rejected() {
+        let f = fixture();
+        let mut keyring = snapshot(&f, 2, crypto::now() + 60);
+
+        crypto::sign_keyring(&f.maint, &mut keyring).unwrap();
+
+        assert!(matches!(
+            check_snapshot(&f.settings, &keyring, &parser::KeyringConfig::default()),
+            Err(TrustError::UnsignedSnapshot)
+        ));
+    }
+
+    #[test]
+    fn expired_snapshot_is_rejected() {
+        let f = fixture();
+
+        assert!(matches!(
+            check_snapshot(&f.settings, &snapshot(&f, 2, crypto::now() - 1), &parser::KeyringConfig::default()),
+            Err(TrustError::ExpiredSnapshot(_))
+        ));
+    }
+
+    #[test]
+    fn lower_serial_is_rejected() {
+        let f       = fixture();
+        let current = snapshot(&f, 2, crypto::now() + 60);
+
+        assert!(matches!(
+            check_snapshot(&f.settings, &snapshot(&f, 1, crypto::now() + 60), &current),
+            Err(TrustError::OldSnapshot { serial: 1, current: 2 })
+        ));
+
+        // same serial with different contents
+        let mut other = f.keyring.clone();
+        other.owners.clear();
+        let mut other = parser::KeyringConfig { serial: 2, expires: crypto::now() + 60, ..other };
+        crypto::sign_keyring(&f.anchor, &mut other).unwrap();
+
+        assert!(matches!(
+            check_snapshot(&f.settings, &other, &current),
+            Err(TrustError::OldSnapshot { serial: 2, current: 2 })
+        ));
+    }
+
+    #[test]
+    fn expired_local_keyring_is_refused() {
+        let _home = testing::home();
+        let f     = fixture();
+
+        parser::save_trust_anchors(&f.settings.trust_anchors).unwrap();
+        parser::save_keyring(&snapshot(&f, 1, crypto::now() - 1)).unwrap();
+        assert!(matches!(load(), Err(Error::Trust(TrustError::ExpiredKeyring(_)))));
+
+        parser::save_keyring(&snapshot(&f, 2, crypto::now() + 60)).unwrap();
+        assert!(load().is_ok());
+    }
+
+    #[test]
+    fn package_of_authorized_maintainer_is_accepted() {
+        let f   = fixture();
+        let web = Web::new(&f.settings, f.keyring.clone()).unwrap();
+
+        assert!(web.verify_pkginfo(&testing::signed(&f.maint, "hello", "1.0"), false).is_ok());
+        assert!(web.verify_pkginfo(&testing::signed(&f.maint, "hello-utils", "1.0"), false).is_ok());
+        assert!(web.verify_pkginfo(&testing::signed(&f.anchor, "anything", "1.0"), false).is_ok());
+    }
+
+    #[test]
+    fn package_of_unauthorized_owner_is_rejected() {
+        let f   = fixture();
+        let web = Web::new(&f.settings, f.keyring.clone()).unwrap();
+
+        assert!(matches!(
+            web.verify_pkginfo(&testing::signed(&f.maint, "glibc", "2.31"), false),
+            Err(ipfs::IPFSError::Unauthorized)
+        ));
+
+        // a package can't provide a name its signer may not publish
+        let mut pkg = testing::pkginfo("hello", "1.0");
+        pkg.provides.push("glibc = 2.31".to_string());
+        crypto::sign_pkginfo(&f.maint, &mut pkg).unwrap();
+
+        assert!(matches!(web.verify_pkginfo(&pkg, false), Err(ipfs::IPFSError::Unauthorized)));
+    }
+
+    #[test]
+    fn package_patterns() {
+        assert!(matches("hello", "hello"));
+        assert!(!matches("hello", "hello-utils"));
+        assert!(matches("hello*", "hello-utils"));
+        assert!(matches("*", "anything"));
+    }
+
+    #[test]
+    fn too_few_cosigners_are_rejected() {
+        let mut f      = fixture();
+        let (bob, key) = testing::keypair();
+
+        f.settings.min_signatures = 2;
+        f.keyring.signers.push(testing::entry("bob", &key, &[&f.anchor]));
+        f.keyring.owners.push(parser::Ownership { key, packages: vec!["hello".to_string()] });
+
+        let web     = Web::new(&f.settings, f.keyring.clone()).unwrap();
+        let mut pkg = testing::signed(&f.maint, "hello", "1.0");
+
+        assert!(matches!(web.verify_pkginfo(&pkg, false), Err(ipfs::IPFSError::MissingSignatures)));
+
+        // co-signatures of keys outside of the keyring don't count
+        crypto::cosign(&testing::keypair().0, &mut pkg).unwrap();
+        assert!(matches!(web.verify_pkginfo(&pkg, false), Err(ipfs::IPFSError::MissingSignatures)));
+
+        crypto::cosign(&bob, &mut pkg).unwrap();
+        assert!(web.verify_pkginfo(&pkg, false).is_ok());
+    }
+
+    #[test]
+    fn cosigner_must_be_authorized() {
+        let mut f      = fixture();
+        let (bob, key) = testing::keypair();
+
+        f.settings.signature_policies.push(settings::SignaturePolicy {
+            packages:       vec!["hello".to_string()],
+            min_signatures: 2
+        });
+        f.keyring.signers.push(testing::entry("bob", &key, &[&f.anchor]));
+
+        let web     = Web::new(&f.settings, f.keyring.clone()).unwrap();
+        let mut pkg = testing::signed(&f.maint, "hello", "1.0");
+
+        crypto::cosign(&bob, &mut pkg).unwrap();
+        assert!(matches!(web.verify_pkginfo(&pkg, false), Err(ipfs::IPFSError::MissingSignatures)));
+    }
+}